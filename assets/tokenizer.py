@@ -3,6 +3,7 @@
 # This software may be used and distributed according to the terms of the Llama 2 Community License Agreement.
 
 import os
+import json
 import struct
 import argparse
 from typing import List
@@ -69,10 +70,24 @@ class Tokenizer:
                 f.write(struct.pack("fI", score, len(bytes)))
                 f.write(bytes)
 
+    def export_golden(self, corpus_path, golden_path):
+        # token ids of every string in the corpus (a json list of strings), with BOS,
+        # for the golden tests of the rust tokenizer
+        with open(corpus_path, encoding='utf-8') as f:
+            corpus = json.load(f)
+        golden = [{"text": s, "ids": self.encode(s, bos=True, eos=False)} for s in corpus]
+        with open(golden_path, 'w', encoding='utf-8') as f:
+            json.dump(golden, f, ensure_ascii=False, indent=1)
+
 if __name__ == "__main__":
     parser = argparse.ArgumentParser()
     parser.add_argument("-t", "--tokenizer-model", type=str, help="optional path to custom tokenizer ")
+    parser.add_argument("--golden", type=str, nargs=2, metavar=("CORPUS", "OUT"),
+                        help="write the token ids of the strings in CORPUS to OUT instead of exporting")
     args = parser.parse_args()
 
     t = Tokenizer(args.tokenizer_model)
-    t.export()
+    if args.golden:
+        t.export_golden(*args.golden)
+    else:
+        t.export()
@@ -0,0 +1,133 @@
+// encode against the llama2.c merge loop on the real 32k vocab, both over the
+// same english document at every size.
+//
+//   cargo bench --bench encode [-- <tokenizer.bin>]
+//
+// the reference is quadratic in the prompt, so at 100k characters it runs for
+// tens of minutes. both run once per size and have to agree on every token.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_llm::tokenizer::Tokenizer;
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 3] = [2_000, 10_000, 100_000];
+
+const SENTENCES: [&str; 16] = [
+    "Once upon a time, there was a little girl named Lily.",
+    "She loved to play outside in the park with her friends.",
+    "One day, she saw a big red ball under a tree.",
+    "\"Can I play with it?\" she asked her mom.",
+    "Her mom smiled and said, \"Yes, but be careful.\"",
+    "The weather was warm, and the birds were singing.",
+    "Tom didn't want to share his toys, so he hid them behind the couch.",
+    "After lunch, they went to the library to find a book about dinosaurs.",
+    "The old man who lived next door had a garden full of tomatoes.",
+    "In 1969, astronauts landed on the Moon for the first time.",
+    "Scientists think the universe is about 13.8 billion years old.",
+    "It was raining so hard that the streets turned into small rivers.",
+    "Everyone laughed when the dog stole the cake from the table.",
+    "At night, the stars looked like tiny lights in a dark blue sky.",
+    "They learned that being kind is more important than winning.",
+    "The end.",
+];
+
+fn document(rng: &mut StdRng, len: usize) -> String {
+    let mut text = String::new();
+    while text.len() < len {
+        text += SENTENCES[rng.gen_range(0..SENTENCES.len())];
+        text += if rng.gen_range(0..6) == 0 {
+            "\n\n"
+        } else {
+            " "
+        };
+    }
+    text.truncate(len);
+    text
+}
+
+// the tokens encode starts merging from: the dummy prefix, then a piece per
+// character or a byte fallback per byte
+fn unmerged(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+    let mut tokens = vec![tokenizer.token_lookup(" ").unwrap()];
+    text.chars().for_each(|c| {
+        let c = c.to_string();
+        match tokenizer.token_lookup(&c) {
+            Some(id) => tokens.push(id),
+            None => tokens.extend(c.bytes().map(|b| b as u32 + 3)),
+        }
+    });
+    tokens
+}
+
+// the merge loop encode used to run, rescanning every pair after each merge
+fn merge_reference(tokenizer: &Tokenizer, mut tokens: Vec<u32>) -> Vec<u32> {
+    loop {
+        let mut best_score = -1e10f32;
+        let mut best_id = None;
+        let mut best_idx = 0;
+
+        tokens.windows(2).enumerate().for_each(|(i, pair)| {
+            let merged_str = format!(
+                "{}{}",
+                tokenizer.vocab[pair[0] as usize], tokenizer.vocab[pair[1] as usize]
+            );
+            if let Some(id) = tokenizer.token_lookup(&merged_str) {
+                if tokenizer.vocab_scores[id as usize] > best_score {
+                    best_score = tokenizer.vocab_scores[id as usize];
+                    best_id = Some(id);
+                    best_idx = i;
+                }
+            }
+        });
+
+        let Some(best_id) = best_id else {
+            return tokens;
+        };
+
+        tokens[best_idx] = best_id;
+        tokens.remove(best_idx + 1);
+    }
+}
+
+fn time<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let res = f();
+    (res, start.elapsed())
+}
+
+fn main() {
+    // cargo bench passes --bench through to the binary
+    let path = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap_or("assets/tokenizer.bin".to_string());
+    let tokenizer = match Tokenizer::new(&path, 32000) {
+        Ok(tokenizer) => tokenizer,
+        Err(err) => {
+            eprintln!("skipping the encode bench, can't load {}: {}", path, err);
+            return;
+        }
+    };
+
+    let mut rng = StdRng::seed_from_u64(19);
+    for len in SIZES {
+        let text = document(&mut rng, len);
+        let (tokens, encode) = time(|| tokenizer.encode(&text, false, false).unwrap());
+        let (reference, merge) = time(|| merge_reference(&tokenizer, unmerged(&tokenizer, &text)));
+        assert_eq!(
+            tokens, reference,
+            "encode and the reference disagree over {} characters",
+            len
+        );
+
+        println!(
+            "{:>7} chars {:>6} tokens: encode {:>10.3?}, reference {:>10.3?} ({:.0}x)",
+            len,
+            tokens.len(),
+            encode,
+            merge,
+            merge.as_secs_f64() / encode.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,37 @@
+#![no_main]
+
+// cargo fuzz run tokenizer_round_trip, from the crate root. Any text must come
+// back from decode_all(encode(text)), and encode_with_offsets must cover it.
+
+use libfuzzer_sys::fuzz_target;
+use rust_llm::tokenizer::Tokenizer;
+use std::sync::LazyLock;
+
+// Tokenizer::with_pieces with a few merged pieces and <sep> as an added token
+static TOKENIZER: LazyLock<Tokenizer> = LazyLock::new(|| {
+    let mut tokenizer = Tokenizer::with_pieces(&[
+        (" S", 0f32),
+        (" Sh", 1f32),
+        ("og", 2f32),
+        ("ogg", 3f32),
+        ("ot", 4f32),
+        ("oth", 5f32),
+        ("  ", 6f32),
+        ("<sep>", 7f32),
+    ]);
+    tokenizer.added_tokens = vec![tokenizer.token_lookup("<sep>").unwrap()];
+    tokenizer
+});
+
+fuzz_target!(|text: &str| {
+    let tokenizer = &*TOKENIZER;
+    let (tokens, offsets) = tokenizer.encode_with_offsets(text, true, true).unwrap();
+    assert_eq!(tokenizer.decode_all(&tokens).unwrap(), text);
+
+    let mut covered = 0;
+    offsets.iter().for_each(|&(start, end)| {
+        assert_eq!(start, covered);
+        covered = end;
+    });
+    assert_eq!(covered, text.len());
+});
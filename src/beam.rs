@@ -0,0 +1,327 @@
+use crate::transformer::{RunState, Transformer};
+
+// Beam search keeps the beam_width most likely continuations at every step.
+// Each beam owns a copy of the RunState, and a beam that is extended by more
+// than one token forks its kv cache into every child. Finished hypotheses are
+// ranked by logprob / len^length_penalty, so length_penalty > 0 favours longer
+// outputs and 0 ranks by raw log-probability.
+pub struct BeamSearch {
+    pub beam_width: usize,
+    pub length_penalty: f32,
+    // stop as soon as beam_width hypotheses have finished, rather than once no
+    // live beam can beat them any more
+    pub early_stopping: bool,
+    // the tokens that finish a hypothesis, the tokenizer's end_tokens
+    pub end_tokens: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    // generated tokens, without the prompt, ending in an end token if finished
+    pub tokens: Vec<u32>,
+    // cumulative log-probability of the tokens
+    pub logprob: f32,
+    pub score: f32,
+}
+
+struct Beam {
+    tokens: Vec<u32>,
+    logprob: f32,
+    // kv cache up to the last token, logits for the one after it
+    state: RunState,
+}
+
+impl BeamSearch {
+    fn score(self: &Self, logprob: f32, len: usize) -> f32 {
+        logprob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+
+    fn hypothesis(self: &Self, tokens: Vec<u32>, logprob: f32) -> Hypothesis {
+        Hypothesis {
+            score: self.score(logprob, tokens.len()),
+            tokens,
+            logprob,
+        }
+    }
+
+    // max_len is the most tokens a beam can grow to before the step limit
+    fn is_done(self: &Self, finished: &[Hypothesis], beams: &[Beam], max_len: usize) -> bool {
+        if beams.is_empty() {
+            return true;
+        }
+        if finished.len() < self.beam_width {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+
+        // log-probabilities only go down, but with length_penalty > 0 a longer beam
+        // divides by more and its score moves towards 0. the best a live beam can
+        // still reach is its log-probability spread over max_len tokens, or its
+        // current score otherwise
+        let worst_finished = finished
+            .iter()
+            .map(|h| h.score)
+            .fold(f32::INFINITY, f32::min);
+        let best_live = beams
+            .iter()
+            .map(|b| {
+                if self.length_penalty > 0f32 {
+                    self.score(b.logprob, max_len.max(b.tokens.len()))
+                } else {
+                    self.score(b.logprob, b.tokens.len())
+                }
+            })
+            .fold(f32::NEG_INFINITY, f32::max);
+        worst_finished >= best_live
+    }
+
+    // returns up to beam_width hypotheses, best first
+    pub fn search(
+        self: &Self,
+        transformer: &mut Transformer,
+        prompt_tokens: &[u32],
+        steps: i32,
+    ) -> Vec<Hypothesis> {
+        let width = self.beam_width.max(1);
+        let steps = steps.min(transformer.config.seq_len);
+        let max_len = (steps as usize).saturating_sub(prompt_tokens.len());
+
+        prompt_tokens.iter().enumerate().for_each(|(pos, &token)| {
+            transformer.forward(token, pos as i32);
+        });
+
+        let mut beams = vec![Beam {
+            tokens: vec![],
+            logprob: 0f32,
+            state: transformer.state.clone(),
+        }];
+        let mut spare_states = vec![];
+        let mut finished: Vec<Hypothesis> = vec![];
+        // position of the last token in the kv caches
+        let mut pos = prompt_tokens.len() as i32 - 1;
+
+        while pos + 1 < steps && !beams.is_empty() {
+            // twice the width, so that width beams survive even if half of them end
+            let mut candidates = vec![];
+            beams.iter().enumerate().for_each(|(b, beam)| {
+                let mut logprobs = beam.state.logits.to_vec();
+                Transformer::log_softmax(&mut logprobs);
+                let mut tokens = (0..logprobs.len()).collect::<Vec<usize>>();
+                let k = (2 * width).min(tokens.len());
+                tokens.select_nth_unstable_by(k - 1, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+                tokens[..k].iter().for_each(|&token| {
+                    candidates.push((beam.logprob + logprobs[token], b, token as u32));
+                });
+            });
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut next_beams = vec![];
+            for (rank, &(logprob, b, token)) in candidates.iter().enumerate() {
+                if next_beams.len() == width {
+                    break;
+                }
+
+                let mut tokens = beams[b].tokens.clone();
+                tokens.push(token);
+                if self.end_tokens.contains(&token) {
+                    if rank < width {
+                        finished.push(self.hypothesis(tokens, logprob));
+                    }
+                    continue;
+                }
+
+                let mut state = spare_states.pop().unwrap_or_else(|| beams[b].state.clone());
+                state.copy_kv_prefix(&beams[b].state, &transformer.config, (pos + 1) as usize);
+                std::mem::swap(&mut transformer.state, &mut state);
+                transformer.forward(token, pos + 1);
+                std::mem::swap(&mut transformer.state, &mut state);
+
+                next_beams.push(Beam {
+                    tokens,
+                    logprob,
+                    state,
+                });
+            }
+
+            spare_states.extend(beams.drain(..).map(|beam| beam.state));
+            beams = next_beams;
+            pos += 1;
+
+            if self.is_done(&finished, &beams, max_len) {
+                break;
+            }
+        }
+
+        // beams cut off by the step limit compete with the finished ones
+        finished.extend(
+            beams
+                .into_iter()
+                .map(|beam| self.hypothesis(beam.tokens, beam.logprob)),
+        );
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(width);
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{BOS_TOKEN, EOS_TOKEN};
+    use crate::transformer::Config;
+
+    const END_TOKENS: [u32; 2] = [BOS_TOKEN, EOS_TOKEN];
+
+    const CONFIG: Config = Config {
+        dim: 16,
+        hidden_dim: 32,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 2,
+        vocab_size: 32,
+        seq_len: 24,
+    };
+
+    // log-probability of the tokens after the prompt, by plain decoding
+    fn replay_logprob(seed: u64, prompt_tokens: &[u32], tokens: &[u32]) -> f32 {
+        let mut transformer = Transformer::random(CONFIG, seed);
+        let all = prompt_tokens
+            .iter()
+            .chain(tokens)
+            .copied()
+            .collect::<Vec<u32>>();
+        let mut logprob = 0f32;
+        for pos in 0..all.len() - 1 {
+            transformer.forward(all[pos], pos as i32);
+            if pos + 1 >= prompt_tokens.len() {
+                let mut logprobs = transformer.state.logits.to_vec();
+                Transformer::log_softmax(&mut logprobs);
+                logprob += logprobs[all[pos + 1] as usize];
+            }
+        }
+        logprob
+    }
+
+    fn greedy(seed: u64, prompt_tokens: &[u32], steps: i32) -> Vec<u32> {
+        let mut transformer = Transformer::random(CONFIG, seed);
+        let mut tokens = vec![];
+        let mut token = prompt_tokens[0];
+        for pos in 0..steps - 1 {
+            transformer.forward(token, pos);
+            token = match prompt_tokens.get(pos as usize + 1) {
+                Some(&t) => t,
+                None => {
+                    let logits = &transformer.state.logits;
+                    let next = (0..logits.len())
+                        .max_by(|&a, &b| logits[a].total_cmp(&logits[b]))
+                        .unwrap() as u32;
+                    tokens.push(next);
+                    if END_TOKENS.contains(&next) {
+                        break;
+                    }
+                    next
+                }
+            };
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_width_one_is_greedy() {
+        let prompt_tokens = [1, 5, 9];
+        for seed in 0..5 {
+            let search = BeamSearch {
+                beam_width: 1,
+                length_penalty: 0f32,
+                early_stopping: true,
+                end_tokens: END_TOKENS.to_vec(),
+            };
+            let mut transformer = Transformer::random(CONFIG, seed);
+            let beams = search.search(&mut transformer, &prompt_tokens, 16);
+            assert_eq!(beams.len(), 1);
+            assert_eq!(beams[0].tokens, greedy(seed, &prompt_tokens, 16));
+        }
+    }
+
+    #[test]
+    fn test_forked_caches_match_plain_decoding() {
+        let prompt_tokens = [1, 7, 3, 12];
+        for seed in 0..5 {
+            let search = BeamSearch {
+                beam_width: 4,
+                length_penalty: 1f32,
+                early_stopping: false,
+                end_tokens: END_TOKENS.to_vec(),
+            };
+            let mut transformer = Transformer::random(CONFIG, seed);
+            let beams = search.search(&mut transformer, &prompt_tokens, CONFIG.seq_len);
+            assert_eq!(beams.len(), 4);
+
+            beams
+                .windows(2)
+                .for_each(|w| assert!(w[0].score >= w[1].score));
+            beams.iter().for_each(|beam| {
+                let expected = replay_logprob(seed, &prompt_tokens, &beam.tokens);
+                assert!(
+                    (beam.logprob - expected).abs() < 1e-3,
+                    "{} != {}",
+                    beam.logprob,
+                    expected
+                );
+                assert_eq!(beam.score, beam.logprob / (beam.tokens.len() as f32));
+                let ended = beam.tokens[..beam.tokens.len() - 1]
+                    .iter()
+                    .any(|t| END_TOKENS.contains(t));
+                assert!(!ended);
+            });
+        }
+    }
+
+    #[test]
+    fn test_live_beam_can_win_by_length() {
+        let search = BeamSearch {
+            beam_width: 1,
+            length_penalty: 1f32,
+            early_stopping: false,
+            end_tokens: END_TOKENS.to_vec(),
+        };
+        let state = Transformer::random(CONFIG, 0).state;
+        // one token at -1 has finished, and a live beam is at -2.2 over two tokens,
+        // scoring -1.1. Two more tokens at -0.1 each end it at -2.4 / 4 = -0.6
+        let finished = [search.hypothesis(vec![EOS_TOKEN], -1f32)];
+        let beams = [Beam {
+            tokens: vec![5, 6],
+            logprob: -2.2,
+            state: state.clone(),
+        }];
+        assert!(search.hypothesis(vec![5, 6, 7, EOS_TOKEN], -2.4).score > finished[0].score);
+        assert!(!search.is_done(&finished, &beams, 4));
+        // with no room to grow it cannot catch up any more
+        assert!(search.is_done(&finished, &beams, 2));
+
+        // without length normalisation, the current score is the best it can do
+        let search = BeamSearch {
+            length_penalty: 0f32,
+            ..search
+        };
+        let finished = [search.hypothesis(vec![EOS_TOKEN], -1f32)];
+        assert!(search.is_done(&finished, &beams, 4));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let search = BeamSearch {
+            beam_width: 3,
+            length_penalty: 0f32,
+            early_stopping: true,
+            end_tokens: END_TOKENS.to_vec(),
+        };
+        let mut transformer = Transformer::random(CONFIG, 3);
+        let beams = search.search(&mut transformer, &[1, 4], 6);
+        beams
+            .iter()
+            .for_each(|beam| assert!(beam.tokens.len() <= 4));
+    }
+}
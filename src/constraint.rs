@@ -0,0 +1,55 @@
+use crate::tokenizer::Tokenize;
+
+// A constraint restricts what generate may sample next: before each draw it
+// masks the logits of tokens that cannot continue a valid output, and after the
+// draw it is told which token was picked.
+pub trait Constraint {
+    fn mask_logits(self: &mut Self, logits: &mut [f32]);
+    fn accept_token(self: &mut Self, token: u32) -> Result<(), String>;
+    // whether the output so far is valid as it stands, i.e. may end here
+    fn is_complete(self: &Self) -> bool;
+
+    // whether anything but an end token may still follow
+    fn can_continue(self: &mut Self, tokenizer: &dyn Tokenize) -> bool {
+        let mut logits = vec![0f32; tokenizer.vocab_size() as usize];
+        self.mask_logits(&mut logits);
+        logits.iter().enumerate().any(|(token, &logit)| {
+            logit > f32::NEG_INFINITY && !tokenizer.is_end_token(token as u32)
+        })
+    }
+}
+
+// What a vocab entry contributes to the output text
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenPiece {
+    Text(String),
+    // bytes that are not valid UTF-8 on their own, like a byte-fallback token or
+    // part of a character in byte-level BPE
+    Bytes(Vec<u8>),
+    // the tokens ending the output, which may only be drawn once the constraint is
+    // complete
+    End,
+    // <unk> and the other control tokens, never drawn
+    Control,
+}
+
+// what each token adds mid-text, where it does not follow BOS
+pub fn token_pieces(tokenizer: &dyn Tokenize) -> Box<[TokenPiece]> {
+    (0..tokenizer.vocab_size())
+        .map(|token| {
+            if tokenizer.is_end_token(token) {
+                return TokenPiece::End;
+            }
+            if tokenizer.is_control(token) {
+                return TokenPiece::Control;
+            }
+            match tokenizer.decode_bytes(token, u32::MAX) {
+                Ok(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(s) => TokenPiece::Text(s.to_string()),
+                    Err(_) => TokenPiece::Bytes(bytes.to_vec()),
+                },
+                Err(_) => TokenPiece::Control,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,240 @@
+use crate::transformer::{RunState, Transformer};
+
+// Contrastive search (https://arxiv.org/abs/2202.06417) picks, among the top_k
+// most likely next tokens, the one maximising
+//   (1 - alpha) * p(v) - alpha * max_j cos(h_v, h_j)
+// where h_v is the final hidden state (state.x) the model produces for v and
+// the h_j are those of every token before it. Candidates that would make the
+// model's representation repeat what it has already seen are penalised, which
+// keeps greedy-like decoding from looping. alpha = 0 is greedy decoding.
+pub struct ContrastiveSearch {
+    pub top_k: usize,
+    pub alpha: f32,
+    // the tokenizer's end_tokens
+    pub end_tokens: Vec<u32>,
+}
+
+impl ContrastiveSearch {
+    // returns the generated tokens, up to and without the end token. on_token is
+    // called with every token as it is picked
+    pub fn generate(
+        self: &Self,
+        transformer: &mut Transformer,
+        prompt_tokens: &[u32],
+        steps: i32,
+        mut on_token: impl FnMut(u32),
+    ) -> Vec<u32> {
+        let steps = steps.min(transformer.config.seq_len) as usize;
+        let top_k = self.top_k.max(1);
+
+        // unit length hidden states of the sequence so far, so cos is a dot product
+        let mut hidden = vec![];
+        prompt_tokens.iter().enumerate().for_each(|(pos, &token)| {
+            transformer.forward(token, pos as i32);
+            hidden.push(unit(&transformer.state.x));
+        });
+
+        // each candidate is forwarded on its own fork of the kv cache, and the
+        // winner's state becomes the current one
+        let mut forks: Vec<RunState> = vec![];
+        let mut tokens = vec![];
+        let mut probs = vec![];
+
+        while prompt_tokens.len() + tokens.len() < steps {
+            let pos = prompt_tokens.len() + tokens.len();
+
+            probs.clear();
+            probs.extend_from_slice(&transformer.state.logits);
+            Transformer::softmax(&mut probs);
+            let mut candidates = (0..probs.len()).collect::<Vec<usize>>();
+            let k = top_k.min(candidates.len());
+            candidates.select_nth_unstable_by(k - 1, |&a, &b| probs[b].total_cmp(&probs[a]));
+            candidates.truncate(k);
+            candidates.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+
+            while forks.len() < k {
+                forks.push(transformer.state.clone());
+            }
+
+            let mut best: Option<(f32, usize, Box<[f32]>)> = None;
+            for (i, &candidate) in candidates.iter().enumerate() {
+                // an end token has no continuation to compare, it competes on confidence
+                let (degeneration, h) = if self.end_tokens.contains(&(candidate as u32)) {
+                    (0f32, Box::default())
+                } else {
+                    forks[i].copy_kv_prefix(&transformer.state, &transformer.config, pos);
+                    std::mem::swap(&mut transformer.state, &mut forks[i]);
+                    transformer.forward(candidate as u32, pos as i32);
+                    std::mem::swap(&mut transformer.state, &mut forks[i]);
+
+                    let h = unit(&forks[i].x);
+                    let max_similarity = hidden
+                        .iter()
+                        .map(|h_j| dot(&h, h_j))
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    (max_similarity, h)
+                };
+
+                let score = (1f32 - self.alpha) * probs[candidate] - self.alpha * degeneration;
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_score, _, _)| score > *best_score)
+                {
+                    best = Some((score, i, h));
+                }
+            }
+
+            let (_, i, h) = best.unwrap();
+            let next = candidates[i] as u32;
+            if self.end_tokens.contains(&next) {
+                break;
+            }
+
+            std::mem::swap(&mut transformer.state, &mut forks[i]);
+            hidden.push(h);
+            tokens.push(next);
+            on_token(next);
+        }
+
+        tokens
+    }
+}
+
+fn unit(x: &[f32]) -> Box<[f32]> {
+    let norm = dot(x, x).sqrt().max(f32::MIN_POSITIVE);
+    x.iter().map(|e| e / norm).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{BOS_TOKEN, EOS_TOKEN};
+    use crate::transformer::Config;
+
+    const CONFIG: Config = Config {
+        dim: 16,
+        hidden_dim: 32,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 2,
+        vocab_size: 32,
+        seq_len: 20,
+    };
+
+    // the final hidden state and logits after running tokens from scratch
+    fn replay(seed: u64, tokens: &[u32]) -> (Box<[f32]>, Box<[f32]>) {
+        let mut transformer = Transformer::random(CONFIG, seed);
+        tokens
+            .iter()
+            .enumerate()
+            .for_each(|(pos, &token)| transformer.forward(token, pos as i32));
+        (unit(&transformer.state.x), transformer.state.logits.clone())
+    }
+
+    // contrastive search without any cache sharing, every candidate replayed
+    // from the start
+    fn naive(
+        seed: u64,
+        search: &ContrastiveSearch,
+        prompt_tokens: &[u32],
+        steps: usize,
+    ) -> Vec<u32> {
+        let mut sequence = prompt_tokens.to_vec();
+        while sequence.len() < steps {
+            let hidden = (1..=sequence.len())
+                .map(|n| replay(seed, &sequence[..n]).0)
+                .collect::<Vec<_>>();
+            let mut probs = replay(seed, &sequence).1.to_vec();
+            Transformer::softmax(&mut probs);
+
+            let mut candidates = (0..probs.len()).collect::<Vec<usize>>();
+            candidates.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+            candidates.truncate(search.top_k);
+
+            let score = |candidate: usize| {
+                let degeneration = if search.end_tokens.contains(&(candidate as u32)) {
+                    0f32
+                } else {
+                    let with = [&sequence[..], &[candidate as u32]].concat();
+                    let h = replay(seed, &with).0;
+                    hidden
+                        .iter()
+                        .map(|h_j| dot(&h, h_j))
+                        .fold(f32::NEG_INFINITY, f32::max)
+                };
+                (1f32 - search.alpha) * probs[candidate] - search.alpha * degeneration
+            };
+            let next = candidates
+                .iter()
+                .copied()
+                .reduce(|best, c| if score(c) > score(best) { c } else { best })
+                .unwrap() as u32;
+            if search.end_tokens.contains(&next) {
+                break;
+            }
+            sequence.push(next);
+        }
+        sequence[prompt_tokens.len()..].to_vec()
+    }
+
+    #[test]
+    fn test_matches_uncached_search() {
+        let prompt_tokens = [1, 6, 11];
+        for seed in 0..3 {
+            for alpha in [0.3f32, 0.6] {
+                let search = ContrastiveSearch {
+                    top_k: 4,
+                    alpha,
+                    end_tokens: vec![BOS_TOKEN, EOS_TOKEN],
+                };
+                let mut transformer = Transformer::random(CONFIG, seed);
+                let mut streamed = vec![];
+                let tokens =
+                    search.generate(&mut transformer, &prompt_tokens, 14, |t| streamed.push(t));
+                assert_eq!(
+                    tokens,
+                    naive(seed, &search, &prompt_tokens, 14),
+                    "seed {} alpha {}",
+                    seed,
+                    alpha
+                );
+                assert_eq!(streamed, tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alpha_zero_is_greedy() {
+        let prompt_tokens = [1, 3];
+        for seed in 0..3 {
+            let search = ContrastiveSearch {
+                top_k: 5,
+                alpha: 0f32,
+                end_tokens: vec![BOS_TOKEN, EOS_TOKEN],
+            };
+            let tokens = search.generate(
+                &mut Transformer::random(CONFIG, seed),
+                &prompt_tokens,
+                16,
+                |_| {},
+            );
+
+            let mut sequence = prompt_tokens.to_vec();
+            while sequence.len() < 16 {
+                let logits = replay(seed, &sequence).1;
+                let next = (0..logits.len())
+                    .max_by(|&a, &b| logits[a].total_cmp(&logits[b]))
+                    .unwrap() as u32;
+                if search.end_tokens.contains(&next) {
+                    break;
+                }
+                sequence.push(next);
+            }
+            assert_eq!(tokens, sequence[prompt_tokens.len()..]);
+        }
+    }
+}
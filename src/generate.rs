@@ -0,0 +1,396 @@
+use crate::constraint::Constraint;
+use crate::sampler::Sampler;
+use crate::stop::{FinishReason, StopStrings};
+use crate::tokenizer::{StreamDecoder, Tokenize};
+use crate::transformer::Transformer;
+
+// max_new_tokens counts generated tokens only, max_context the prompt as well
+// and is capped by the model's seq_len. with logprobs set, every generated token
+// reports its log-probability and that many of the most likely alternatives
+pub struct GenerateParams<'a> {
+    // text ending the output, matched across token boundaries and left out
+    pub stop: &'a [&'a str],
+    pub max_new_tokens: usize,
+    pub max_context: usize,
+    pub logprobs: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedToken {
+    pub token: u32,
+    pub piece: String,
+    // from the raw logits of the model, before penalties, logit bias,
+    // constraints and temperature
+    pub logprob: Option<f32>,
+    // most likely first
+    pub top_logprobs: Vec<TokenLogprob>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub prompt_tokens: Vec<u32>,
+    pub tokens: Vec<GeneratedToken>,
+    // the output with any stop string trimmed
+    pub text: String,
+    pub finish_reason: FinishReason,
+}
+
+// on_text receives the output as it becomes final, which lags the tokens while
+// it could still be the start of a stop string
+pub fn generate(
+    transformer: &mut Transformer,
+    tokenizer: &dyn Tokenize,
+    sampler: &mut Sampler,
+    mut constraint: Option<&mut dyn Constraint>,
+    prompt: &str,
+    params: &GenerateParams,
+    mut on_text: impl FnMut(&str),
+) -> Result<Generation, String> {
+    let prompt_tokens = tokenizer.encode(prompt, true, false)?;
+    let max_context = params.max_context.min(transformer.config.seq_len as usize);
+    if prompt_tokens.len() > max_context {
+        return Err(format!(
+            "prompt of {} tokens does not fit a context of {}",
+            prompt_tokens.len(),
+            max_context
+        ));
+    }
+    sampler.reset();
+
+    let prompt_len = prompt_tokens.len();
+    prompt_tokens[..prompt_len - 1]
+        .iter()
+        .enumerate()
+        .for_each(|(pos, &token)| transformer.forward(token, pos as i32));
+
+    let mut decoder = StreamDecoder::new(tokenizer, prompt_tokens[prompt_len - 1]);
+    let mut stop_strings = StopStrings::new(params.stop);
+    // every token after BOS, the history the penalties look at
+    let mut out_tokens = prompt_tokens[1..].to_vec();
+    let mut token = prompt_tokens[prompt_len - 1];
+    let mut generated = vec![];
+    let mut text = String::new();
+    let mut logprobs = vec![];
+
+    let mut finish_reason = loop {
+        let len = out_tokens.len() + 1;
+        if len - prompt_len >= params.max_new_tokens || len >= max_context {
+            break FinishReason::Length;
+        }
+
+        transformer.forward(token, len as i32 - 1);
+
+        if params.logprobs.is_some() {
+            logprobs.clear();
+            logprobs.extend_from_slice(&transformer.state.logits);
+            Transformer::log_softmax(&mut logprobs);
+        }
+
+        if let Some(constraint) = constraint.as_deref_mut() {
+            constraint.mask_logits(&mut transformer.state.logits);
+        }
+        let next = sampler.sample(&mut transformer.state.logits[..], &out_tokens) as u32;
+        if let Some(constraint) = constraint.as_deref_mut() {
+            constraint.accept_token(next)?;
+        }
+
+        if tokenizer.is_end_token(next) {
+            break FinishReason::Eos;
+        }
+
+        out_tokens.push(next);
+
+        let (emitted, stopped) = stop_strings.push(&decoder.push(next)?);
+        on_text(&emitted);
+        text.push_str(&emitted);
+        generated.push(GeneratedToken {
+            token: next,
+            piece: tokenizer.piece(next),
+            logprob: params.logprobs.map(|_| logprobs[next as usize]),
+            top_logprobs: top_logprobs(&logprobs, params.logprobs.unwrap_or(0)),
+        });
+        if stopped {
+            break FinishReason::Stop;
+        }
+
+        if let Some(constraint) = constraint.as_deref_mut() {
+            if constraint.is_complete() && !constraint.can_continue(tokenizer) {
+                break FinishReason::Constraint;
+            }
+        }
+
+        token = next;
+    };
+
+    if finish_reason != FinishReason::Stop {
+        // a character left incomplete still reaches the stop strings
+        let (emitted, stopped) = stop_strings.push(&decoder.finish());
+        let held = if stopped {
+            finish_reason = FinishReason::Stop;
+            emitted
+        } else {
+            emitted + &stop_strings.finish()
+        };
+        on_text(&held);
+        text.push_str(&held);
+    }
+
+    Ok(Generation {
+        prompt_tokens,
+        tokens: generated,
+        text,
+        finish_reason,
+    })
+}
+
+// the n largest log-probabilities, most likely first
+fn top_logprobs(logprobs: &[f32], n: usize) -> Vec<TokenLogprob> {
+    let mut top = logprobs
+        .iter()
+        .enumerate()
+        .map(|(token, &logprob)| TokenLogprob {
+            token: token as u32,
+            logprob,
+        })
+        .collect::<Vec<TokenLogprob>>();
+    let n = n.min(top.len());
+    if n == 0 {
+        return vec![];
+    }
+
+    top.select_nth_unstable_by(n - 1, |a, b| b.logprob.total_cmp(&a.logprob));
+    top.truncate(n);
+    top.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex_constraint::RegexConstraint;
+    use crate::tiktoken::Tiktoken;
+    use crate::tokenizer::Tokenizer;
+    use crate::transformer::Config;
+
+    fn model(tokenizer: &dyn Tokenize, seed: u64) -> Transformer {
+        let config = Config {
+            dim: 16,
+            hidden_dim: 32,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 2,
+            vocab_size: tokenizer.vocab_size() as i32,
+            seq_len: 32,
+        };
+        Transformer::random(config, seed)
+    }
+
+    fn params(logprobs: Option<usize>) -> GenerateParams<'static> {
+        GenerateParams {
+            stop: &[],
+            max_new_tokens: 12,
+            max_context: 32,
+            logprobs,
+        }
+    }
+
+    #[test]
+    fn test_logprobs_are_from_raw_logits() {
+        let tokenizer = Tokenizer::with_pieces(&[]);
+        let mut sampler = Sampler {
+            repetition_penalty: 1.5,
+            logit_bias: [(100, 3f32)].into(),
+            ..Sampler::new(tokenizer.vocab_size as i32, 0.7, 0.9, 5)
+        };
+        let generation = generate(
+            &mut model(&tokenizer, 1),
+            &tokenizer,
+            &mut sampler,
+            None,
+            "ab",
+            &params(Some(3)),
+            |_| {},
+        )
+        .unwrap();
+
+        // replay the output through the bare model
+        let mut transformer = model(&tokenizer, 1);
+        let all = generation
+            .prompt_tokens
+            .iter()
+            .chain(generation.tokens.iter().map(|t| &t.token))
+            .copied()
+            .collect::<Vec<u32>>();
+        let prompt_len = generation.prompt_tokens.len();
+        for (i, generated) in generation.tokens.iter().enumerate() {
+            if i == 0 {
+                (0..prompt_len).for_each(|pos| transformer.forward(all[pos], pos as i32));
+            } else {
+                let pos = prompt_len + i - 1;
+                transformer.forward(all[pos], pos as i32);
+            }
+            let mut expected = transformer.state.logits.to_vec();
+            Transformer::log_softmax(&mut expected);
+
+            let logprob = generated.logprob.unwrap();
+            assert!((logprob - expected[generated.token as usize]).abs() < 1e-5);
+
+            assert_eq!(generated.top_logprobs.len(), 3);
+            let max = expected.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            assert!((generated.top_logprobs[0].logprob - max).abs() < 1e-5);
+            generated.top_logprobs.windows(2).for_each(|w| {
+                assert!(w[0].logprob >= w[1].logprob);
+            });
+            generated.top_logprobs.iter().for_each(|alternative| {
+                assert!((alternative.logprob - expected[alternative.token as usize]).abs() < 1e-5);
+            });
+        }
+    }
+
+    #[test]
+    fn test_greedy_picks_top_alternative() {
+        let tokenizer = Tokenizer::with_pieces(&[]);
+        let mut sampler = Sampler::new(tokenizer.vocab_size as i32, 0f32, 0.9, 5);
+        let generation = generate(
+            &mut model(&tokenizer, 2),
+            &tokenizer,
+            &mut sampler,
+            None,
+            "hi",
+            &params(Some(1)),
+            |_| {},
+        )
+        .unwrap();
+        generation.tokens.iter().for_each(|t| {
+            assert_eq!(t.top_logprobs[0].token, t.token);
+            assert_eq!(t.top_logprobs[0].logprob, t.logprob.unwrap());
+        });
+
+        let generation = generate(
+            &mut model(&tokenizer, 2),
+            &tokenizer,
+            &mut sampler,
+            None,
+            "hi",
+            &params(None),
+            |_| {},
+        )
+        .unwrap();
+        generation.tokens.iter().for_each(|t| {
+            assert_eq!(t.logprob, None);
+            assert!(t.top_logprobs.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_finish_reasons() {
+        let tokenizer = Tokenizer::with_pieces(&[]);
+        let mut sampler = Sampler::new(tokenizer.vocab_size as i32, 1f32, 0.9, 5);
+        let mut run = |pattern: &str, stop: &[&str], max_new_tokens: usize| {
+            let mut constraint = RegexConstraint::new(pattern, &tokenizer).unwrap();
+            let mut streamed = String::new();
+            let generation = generate(
+                &mut model(&tokenizer, 3),
+                &tokenizer,
+                &mut sampler,
+                Some(&mut constraint),
+                "x",
+                &GenerateParams {
+                    stop,
+                    max_new_tokens,
+                    ..params(None)
+                },
+                |text| streamed.push_str(text),
+            )
+            .unwrap();
+            assert_eq!(streamed, generation.text);
+            (generation.text, generation.finish_reason)
+        };
+
+        assert_eq!(
+            run("abc def", &[], 12),
+            ("abc def".to_string(), FinishReason::Constraint)
+        );
+        assert_eq!(
+            run("abc def", &["c d"], 12),
+            ("ab".to_string(), FinishReason::Stop)
+        );
+        assert_eq!(
+            run("abc def", &[], 3),
+            ("abc".to_string(), FinishReason::Length)
+        );
+
+        let (text, finish_reason) = run("abc( def)?", &[], 12);
+        assert!(text == "abc" || text == "abc def");
+        assert_eq!(
+            finish_reason,
+            if text == "abc" {
+                FinishReason::Eos
+            } else {
+                FinishReason::Constraint
+            }
+        );
+    }
+
+    #[test]
+    fn test_prompt_longer_than_context() {
+        let tokenizer = Tokenizer::with_pieces(&[]);
+        let mut sampler = Sampler::new(tokenizer.vocab_size as i32, 0f32, 0.9, 5);
+        let result = generate(
+            &mut model(&tokenizer, 4),
+            &tokenizer,
+            &mut sampler,
+            None,
+            "a very long prompt",
+            &GenerateParams {
+                max_context: 8,
+                ..params(None)
+            },
+            |_| {},
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_byte_level_tokenizer() {
+        let mut vocab = (0..=255u8).map(|b| vec![b]).collect::<Vec<Vec<u8>>>();
+        vocab.extend(["ab", " c", "é"].map(|t| t.as_bytes().to_vec()));
+        vocab.push(vec![0xf0, 0x9f]);
+        let tokenizer = Tiktoken::from_vocab(vocab).unwrap();
+        let mut sampler = Sampler::new(tokenizer.vocab_size as i32, 1f32, 1f32, 6);
+
+        for seed in 0..4 {
+            let mut streamed = String::new();
+            let generation = generate(
+                &mut model(&tokenizer, seed),
+                &tokenizer,
+                &mut sampler,
+                None,
+                "ab c",
+                &params(Some(2)),
+                |text| streamed.push_str(text),
+            )
+            .unwrap();
+            assert_eq!(generation.prompt_tokens[0], tokenizer.bos_token);
+
+            // the text is the bytes of the tokens, whole characters or not
+            let bytes = generation
+                .tokens
+                .iter()
+                .flat_map(|t| tokenizer.vocab[t.token as usize].clone())
+                .collect::<Vec<u8>>();
+            assert_eq!(generation.text, String::from_utf8_lossy(&bytes));
+            assert_eq!(streamed, generation.text);
+            assert!(generation
+                .tokens
+                .iter()
+                .all(|t| !tokenizer.is_end_token(t.token)));
+        }
+    }
+}
@@ -0,0 +1,815 @@
+use crate::constraint::{self, Constraint, TokenPiece};
+use crate::tokenizer::Tokenize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// GBNF grammars, as used by llama.cpp:
+//
+//     root ::= expr
+//     expr ::= term ([-+*/] term)*
+//     term ::= [0-9]+ | "(" expr ")"
+//
+// Rules are made of "literals" with \n, \t, \xHH and \uHHHH escapes, [a-z]
+// character classes (negated with [^...]), `.` for any character, rule
+// references and (groups), each optionally followed by *, + or ?. Alternatives
+// are separated by |, and # starts a comment. A rule ends at the end of its line
+// unless it continues inside a group or on a line starting with |.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    // one character in any of the ranges, or in none of them when negated
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+// rule -> alternatives -> sequence of elements
+type Alternatives = Vec<Vec<Element>>;
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    rules: Vec<Alternatives>,
+    root: usize,
+}
+
+// position of the next element to match within an alternative of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+// A parse in progress: the top frame is the element to match next, the frames
+// below are where to continue once it is done. An empty stack has matched root.
+type Stack = Vec<Pos>;
+
+struct Parser<'a> {
+    src: &'a [char],
+    pos: usize,
+    symbols: HashMap<String, usize>,
+    rules: Vec<Option<Alternatives>>,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(self: &Self, msg: &str) -> Result<T, String> {
+        let line = self.src[..self.pos].iter().filter(|&&c| c == '\n').count() + 1;
+        Err(format!("grammar error on line {}: {}", line, msg))
+    }
+
+    fn peek(self: &Self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(self: &mut Self) -> Result<char, String> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn expect(self: &mut Self, s: &str) -> Result<(), String> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                return self.error(&format!("expected '{}'", s));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_space(self: &mut Self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == ' ' || c == '\t' || c == '\r' || (newlines && c == '\n') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn parse_name(self: &mut Self) -> Result<String, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(Parser::is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn symbol(self: &mut Self, name: &str) -> usize {
+        match self.symbols.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = self.rules.len();
+                self.symbols.insert(name.to_string(), id);
+                self.rules.push(None);
+                id
+            }
+        }
+    }
+
+    fn new_rule(self: &mut Self, alternatives: Alternatives) -> usize {
+        self.rules.push(Some(alternatives));
+        self.rules.len() - 1
+    }
+
+    fn parse_char(self: &mut Self) -> Result<char, String> {
+        match self.next()? {
+            '\\' => {
+                let hex = |parser: &mut Parser, n: usize| {
+                    let start = parser.pos;
+                    parser.pos = (parser.pos + n).min(parser.src.len());
+                    let digits = parser.src[start..parser.pos].iter().collect::<String>();
+                    u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .map_or_else(|| parser.error("invalid escape"), Ok)
+                };
+                match self.next()? {
+                    'n' => Ok('\n'),
+                    'r' => Ok('\r'),
+                    't' => Ok('\t'),
+                    'x' => hex(self, 2),
+                    'u' => hex(self, 4),
+                    'U' => hex(self, 8),
+                    c @ ('\\' | '"' | '[' | ']' | '-' | '^') => Ok(c),
+                    _ => self.error("unknown escape"),
+                }
+            }
+            c => Ok(c),
+        }
+    }
+
+    fn parse_sequence(self: &mut Self, nested: bool) -> Result<Vec<Element>, String> {
+        let mut elems = vec![];
+        // start of the last symbol, a whole literal counts as one for repetition
+        let mut last_start = None;
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    last_start = Some(elems.len());
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        elems.push(Element::Char {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                    self.pos += 1;
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    last_start = Some(elems.len());
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.parse_char()?;
+                        let hi = if self.peek() == Some('-')
+                            && self.src.get(self.pos + 1) != Some(&']')
+                        {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    elems.push(Element::Char { ranges, negated });
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    last_start = Some(elems.len());
+                    elems.push(Element::Char {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.parse_alternatives(true)?;
+                    self.expect(")")?;
+                    last_start = Some(elems.len());
+                    let rule = self.new_rule(alternatives);
+                    elems.push(Element::Rule(rule));
+                }
+                Some(c @ ('*' | '+' | '?')) => {
+                    self.pos += 1;
+                    let Some(start) = last_start.take() else {
+                        return self.error(&format!("'{}' must follow a symbol", c));
+                    };
+                    let symbol = elems.split_off(start);
+                    let rule = self.rules.len();
+                    let mut repeated = symbol.clone();
+                    repeated.push(Element::Rule(rule));
+                    let alternatives = match c {
+                        '*' => vec![repeated, vec![]],
+                        '+' => vec![repeated, symbol],
+                        _ => vec![symbol, vec![]],
+                    };
+                    self.new_rule(alternatives);
+                    elems.push(Element::Rule(rule));
+                }
+                Some(c) if Parser::is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    last_start = Some(elems.len());
+                    let rule = self.symbol(&name);
+                    elems.push(Element::Rule(rule));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+
+        Ok(elems)
+    }
+
+    fn parse_alternatives(self: &mut Self, nested: bool) -> Result<Alternatives, String> {
+        let mut alternatives = vec![self.parse_sequence(nested)?];
+        loop {
+            // a top level rule may carry on with | at the start of the next line
+            let restart = self.pos;
+            self.skip_space(true);
+            if self.peek() != Some('|') {
+                self.pos = restart;
+                break;
+            }
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let src = src.chars().collect::<Vec<char>>();
+        let mut parser = Parser {
+            src: &src,
+            pos: 0,
+            symbols: HashMap::new(),
+            rules: vec![],
+        };
+
+        loop {
+            parser.skip_space(true);
+            if parser.peek().is_none() {
+                break;
+            }
+            let name = parser.parse_name()?;
+            parser.skip_space(false);
+            parser.expect("::=")?;
+            parser.skip_space(true);
+            let alternatives = parser.parse_alternatives(false)?;
+            let rule = parser.symbol(&name);
+            if parser.rules[rule].is_some() {
+                return parser.error(&format!("rule '{}' is defined twice", name));
+            }
+            parser.rules[rule] = Some(alternatives);
+
+            parser.skip_space(false);
+            if parser.peek().is_some_and(|c| c != '\n') {
+                return parser.error("expected the end of the rule");
+            }
+        }
+
+        for (name, &rule) in parser.symbols.iter() {
+            if parser.rules[rule].is_none() {
+                return Err(format!("grammar error: rule '{}' is not defined", name));
+            }
+        }
+        let Some(&root) = parser.symbols.get("root") else {
+            return Err("grammar error: there is no 'root' rule".to_string());
+        };
+
+        let grammar = Grammar {
+            rules: parser.rules.into_iter().map(Option::unwrap).collect(),
+            root,
+        };
+        grammar.check_left_recursion(&parser.symbols)?;
+        Ok(grammar)
+    }
+
+    // expanding a left recursive rule would never reach a character to match
+    fn check_left_recursion(self: &Self, symbols: &HashMap<String, usize>) -> Result<(), String> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in 0..self.rules.len() {
+                if !nullable[rule]
+                    && self.rules[rule].iter().any(|seq| {
+                        seq.iter()
+                            .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                    })
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // rules that can be expanded first, without consuming a character
+        let leftmost = |rule: usize| {
+            let mut refs = vec![];
+            for seq in &self.rules[rule] {
+                for elem in seq {
+                    match elem {
+                        Element::Rule(r) => {
+                            refs.push(*r);
+                            if !nullable[*r] {
+                                break;
+                            }
+                        }
+                        Element::Char { .. } => break,
+                    }
+                }
+            }
+            refs
+        };
+
+        // 0: unvisited, 1: on the current path, 2: done
+        let mut state = vec![0u8; self.rules.len()];
+        fn visit(
+            rule: usize,
+            state: &mut [u8],
+            leftmost: &dyn Fn(usize) -> Vec<usize>,
+        ) -> Option<usize> {
+            state[rule] = 1;
+            for next in leftmost(rule) {
+                if state[next] == 1 {
+                    return Some(next);
+                }
+                if state[next] == 0 {
+                    if let Some(r) = visit(next, state, leftmost) {
+                        return Some(r);
+                    }
+                }
+            }
+            state[rule] = 2;
+            None
+        }
+
+        for rule in 0..self.rules.len() {
+            if state[rule] == 0 {
+                if let Some(r) = visit(rule, &mut state, &leftmost) {
+                    let name = symbols
+                        .iter()
+                        .find(|(_, &id)| id == r)
+                        .map_or("<group>", |(name, _)| name.as_str());
+                    return Err(format!("grammar error: rule '{}' is left recursive", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn element(self: &Self, pos: &Pos) -> Option<&Element> {
+        self.rules[pos.rule][pos.alt].get(pos.elem)
+    }
+
+    // expands rule references until every stack has a character on top or is empty
+    fn advance_stack(self: &Self, mut stack: Stack, out: &mut Vec<Stack>) {
+        let Some(top) = stack.last().copied() else {
+            out.push(stack);
+            return;
+        };
+
+        match self.element(&top) {
+            None => {
+                stack.pop();
+                self.advance_stack(stack, out);
+            }
+            Some(Element::Char { .. }) => out.push(stack),
+            Some(Element::Rule(rule)) => {
+                for alt in 0..self.rules[*rule].len() {
+                    let mut next = stack.clone();
+                    let parent = next.last_mut().unwrap();
+                    parent.elem += 1;
+                    // nothing left to do in the parent, don't keep it around
+                    if self.element(parent).is_none() {
+                        next.pop();
+                    }
+                    next.push(Pos {
+                        rule: *rule,
+                        alt,
+                        elem: 0,
+                    });
+                    self.advance_stack(next, out);
+                }
+            }
+        }
+    }
+
+    fn initial_stacks(self: &Self) -> Vec<Stack> {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            let start = Pos {
+                rule: self.root,
+                alt,
+                elem: 0,
+            };
+            self.advance_stack(vec![start], &mut stacks);
+        }
+        stacks.sort_unstable();
+        stacks.dedup();
+        stacks
+    }
+
+    // whether some character in lo..=hi can be on top of the stack
+    fn can_match(self: &Self, stack: &Stack, lo: u32, hi: u32) -> bool {
+        match stack.last().and_then(|top| self.element(top)) {
+            Some(Element::Char { ranges, negated }) => {
+                if *negated {
+                    !ranges
+                        .iter()
+                        .any(|&(a, b)| a as u32 <= lo && hi <= b as u32)
+                } else {
+                    ranges
+                        .iter()
+                        .any(|&(a, b)| a as u32 <= hi && lo <= b as u32)
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn accept_char(self: &Self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = vec![];
+        for stack in stacks {
+            if self.can_match(stack, c as u32, c as u32) {
+                let mut next = stack.clone();
+                next.last_mut().unwrap().elem += 1;
+                self.advance_stack(next, &mut out);
+            }
+        }
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+
+    fn accept_str(self: &Self, stacks: &[Stack], s: &str) -> Vec<Stack> {
+        let mut chars = s.chars();
+        let Some(first) = chars.next() else {
+            return stacks.to_vec();
+        };
+        let mut stacks = self.accept_char(stacks, first);
+        for c in chars {
+            if stacks.is_empty() {
+                break;
+            }
+            stacks = self.accept_char(&stacks, c);
+        }
+        stacks
+    }
+
+    pub fn matches(self: &Self, text: &str) -> bool {
+        self.accept_str(&self.initial_stacks(), text)
+            .iter()
+            .any(|stack| stack.is_empty())
+    }
+}
+
+// How far a byte token takes a pending utf-8 sequence
+enum Utf8Step {
+    Char(char),
+    // code points the sequence can still complete to
+    Partial(u32, u32),
+    Invalid,
+}
+
+fn utf8_step(pending: &[u8], b: u8) -> Utf8Step {
+    let mut bytes = pending.to_vec();
+    bytes.push(b);
+    match std::str::from_utf8(&bytes) {
+        Ok(s) => Utf8Step::Char(s.chars().next().unwrap()),
+        Err(e) if e.error_len().is_some() => Utf8Step::Invalid,
+        Err(_) => {
+            let (len, min, mut lo) = match bytes[0] {
+                0xC0..=0xDF => (2, 0x80, (bytes[0] & 0x1F) as u32),
+                0xE0..=0xEF => (3, 0x800, (bytes[0] & 0x0F) as u32),
+                _ => (4, 0x10000, (bytes[0] & 0x07) as u32),
+            };
+            bytes[1..]
+                .iter()
+                .for_each(|b| lo = (lo << 6) | (b & 0x3F) as u32);
+            let mut hi = lo;
+            (bytes.len()..len).for_each(|_| {
+                lo <<= 6;
+                hi = (hi << 6) | 0x3F;
+            });
+            Utf8Step::Partial(lo.max(min), hi.min(char::MAX as u32))
+        }
+    }
+}
+
+// Masks generation down to the tokens that keep the output a prefix of a
+// sentence of the grammar. Byte-fallback tokens, and the tokens of byte-level
+// BPE that split a character, are assembled into characters as they come, an
+// incomplete sequence is allowed while some character it can still become is.
+pub struct GrammarConstraint {
+    grammar: Grammar,
+    pieces: Arc<[TokenPiece]>,
+    stacks: Vec<Stack>,
+    pending: Vec<u8>,
+}
+
+impl GrammarConstraint {
+    pub fn new(grammar: Grammar, tokenizer: &dyn Tokenize) -> Self {
+        let stacks = grammar.initial_stacks();
+        GrammarConstraint {
+            grammar,
+            pieces: Arc::from(constraint::token_pieces(tokenizer)),
+            stacks,
+            pending: vec![],
+        }
+    }
+
+    // stacks and pending bytes after the token, None if the grammar rejects it
+    fn step(self: &Self, token: u32) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let stacks = match self.pieces.get(token as usize)? {
+            TokenPiece::End => {
+                return self.is_complete().then(|| (self.stacks.clone(), vec![]));
+            }
+            TokenPiece::Control => return None,
+            TokenPiece::Text(s) if self.pending.is_empty() => {
+                self.grammar.accept_str(&self.stacks, s)
+            }
+            TokenPiece::Text(_) => return None,
+            TokenPiece::Bytes(bytes) => return self.step_bytes(bytes),
+        };
+        (!stacks.is_empty()).then_some((stacks, vec![]))
+    }
+
+    // bytes go through the pending utf-8 sequence one at a time, and may leave
+    // one unfinished at the end
+    fn step_bytes(self: &Self, bytes: &[u8]) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let mut stacks = self.stacks.clone();
+        let mut pending = self.pending.clone();
+        for &b in bytes {
+            match utf8_step(&pending, b) {
+                Utf8Step::Char(c) => {
+                    stacks = self.grammar.accept_char(&stacks, c);
+                    pending.clear();
+                    if stacks.is_empty() {
+                        return None;
+                    }
+                }
+                Utf8Step::Partial(lo, hi) => {
+                    if !stacks
+                        .iter()
+                        .any(|stack| self.grammar.can_match(stack, lo, hi))
+                    {
+                        return None;
+                    }
+                    pending.push(b);
+                }
+                Utf8Step::Invalid => return None,
+            }
+        }
+        Some((stacks, pending))
+    }
+}
+
+impl Constraint for GrammarConstraint {
+    fn mask_logits(self: &mut Self, logits: &mut [f32]) {
+        logits.iter_mut().enumerate().for_each(|(token, logit)| {
+            if self.step(token as u32).is_none() {
+                *logit = f32::NEG_INFINITY;
+            }
+        });
+    }
+
+    fn accept_token(self: &mut Self, token: u32) -> Result<(), String> {
+        let (stacks, pending) = self
+            .step(token)
+            .ok_or_else(|| format!("token {} is not allowed by the grammar", token))?;
+        self.stacks = stacks;
+        self.pending = pending;
+        Ok(())
+    }
+
+    fn is_complete(self: &Self) -> bool {
+        self.pending.is_empty() && self.stacks.iter().any(|stack| stack.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Tokenizer;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const ARITHMETIC: &str = r#"
+        root ::= expr
+        expr ::= term ([-+*/] term)*
+        term ::= num | "(" expr ")"
+        num  ::= [0-9]+
+    "#;
+
+    const LIST: &str = r#"
+        # a list of lowercase words, like ["ab", "c"]
+        root ::= "[" (
+                    item ("," ws item)*
+                 )? "]"
+        item ::= "\"" [a-z]+ "\""
+        ws   ::= " "?
+    "#;
+
+    fn tokenizer() -> Tokenizer {
+        Tokenizer::with_pieces(&[
+            ("12", 1f32),
+            ("+(", 1f32),
+            ("))", 1f32),
+            ("1+", 1f32),
+            ("[\"", 1f32),
+            ("\",", 1f32),
+            ("\"]", 1f32),
+            (", \"", 1f32),
+            ("ab", 1f32),
+            ("abc", 1f32),
+            (" ab", 1f32),
+            ("αβ", 1f32),
+        ])
+    }
+
+    fn allowed(constraint: &mut GrammarConstraint, tokenizer: &Tokenizer) -> Vec<String> {
+        let mut logits = vec![0f32; tokenizer.vocab_size as usize];
+        constraint.mask_logits(&mut logits);
+        let mut allowed = logits
+            .iter()
+            .enumerate()
+            .filter(|(_, &l)| l > f32::NEG_INFINITY)
+            .map(|(t, _)| tokenizer.vocab[t].clone())
+            .collect::<Vec<String>>();
+        allowed.sort();
+        allowed
+    }
+
+    fn feed(constraint: &mut GrammarConstraint, tokenizer: &Tokenizer, pieces: &[&str]) {
+        pieces.iter().for_each(|p| {
+            let token = tokenizer.token_lookup(p).unwrap();
+            constraint.accept_token(token).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_matches_arithmetic() {
+        let grammar = Grammar::parse(ARITHMETIC).unwrap();
+        ["1", "12+3", "(1+2)*3", "((4))", "1/(2-3)*45"]
+            .iter()
+            .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        ["", "1+", "(1", "1)", "a", "1 + 2", "()"]
+            .iter()
+            .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+    }
+
+    #[test]
+    fn test_matches_list() {
+        let grammar = Grammar::parse(LIST).unwrap();
+        ["[]", "[\"a\"]", "[\"ab\", \"c\"]", "[\"ab\",\"c\",\"de\"]"]
+            .iter()
+            .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        ["[", "[\"\"]", "[\"a\",]", "[\"A\"]", "[\"a\"  ,\"b\"]"]
+            .iter()
+            .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Grammar::parse("expr ::= \"a\"")
+            .unwrap_err()
+            .contains("root"));
+        assert!(Grammar::parse("root ::= foo").unwrap_err().contains("foo"));
+        assert!(Grammar::parse("root ::= root \"a\" | \"b\"")
+            .unwrap_err()
+            .contains("left recursive"));
+        assert!(Grammar::parse("root ::= \"a\"?* ")
+            .unwrap_err()
+            .contains("must follow"));
+        assert!(Grammar::parse("root ::= (\"a\"").is_err());
+        assert!(Grammar::parse("root ::= [^\\n]* | \"\\u00e9\" . \"\\x41\"").is_ok());
+    }
+
+    #[test]
+    fn test_mask_multi_char_tokens() {
+        let tokenizer = tokenizer();
+        let mut constraint =
+            GrammarConstraint::new(Grammar::parse(ARITHMETIC).unwrap(), &tokenizer);
+
+        // single characters can also come as byte-fallback tokens
+        let digits = (0..10).map(|d| d.to_string());
+        let bytes = (b'0'..=b'9').chain(*b"(").map(|b| format!("<0x{:02X}>", b));
+        let mut expected = digits
+            .chain(bytes)
+            .chain(["(", "12", "1+"].map(String::from))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(allowed(&mut constraint, &tokenizer), expected);
+
+        feed(&mut constraint, &tokenizer, &["1+", "(", "12"]);
+        assert!(!constraint.is_complete());
+        let allowed_now = allowed(&mut constraint, &tokenizer);
+        [")", "+(", "*", "12"]
+            .iter()
+            .for_each(|p| assert!(allowed_now.contains(&p.to_string()), "{}", p));
+        // only one paren is open
+        assert!(!allowed_now.contains(&"))".to_string()));
+        assert!(!allowed_now.contains(&"\n<s>\n".to_string()));
+        let closing = tokenizer.token_lookup("))").unwrap();
+        assert!(constraint.accept_token(closing).is_err());
+
+        feed(&mut constraint, &tokenizer, &["+(", "1", "))"]);
+        assert!(constraint.is_complete());
+        assert!(allowed(&mut constraint, &tokenizer).contains(&"\n<s>\n".to_string()));
+    }
+
+    #[test]
+    fn test_mask_byte_fallback_tokens() {
+        let tokenizer = tokenizer();
+        let grammar = Grammar::parse("root ::= \"x\" [α-ω]+").unwrap();
+        let mut constraint = GrammarConstraint::new(grammar, &tokenizer);
+        feed(&mut constraint, &tokenizer, &["x"]);
+
+        let allowed_now = allowed(&mut constraint, &tokenizer);
+        // α..ω are U+03B1..U+03C9, two bytes behind 0xCE or 0xCF
+        assert_eq!(allowed_now, vec!["<0xCE>", "<0xCF>", "αβ"]);
+
+        feed(&mut constraint, &tokenizer, &["<0xCE>"]);
+        assert!(!constraint.is_complete());
+        let allowed_now = allowed(&mut constraint, &tokenizer);
+        // continuations 0xB1..0xBF complete α..ο
+        let expected = (0xB1..=0xBF)
+            .map(|b| format!("<0x{:02X}>", b))
+            .collect::<Vec<_>>();
+        assert_eq!(allowed_now, expected);
+
+        feed(&mut constraint, &tokenizer, &["<0xBC>"]);
+        assert!(constraint.is_complete());
+    }
+
+    #[test]
+    fn test_masked_generation_always_parses() {
+        let tokenizer = tokenizer();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for src in [ARITHMETIC, LIST] {
+            let grammar = Grammar::parse(src).unwrap();
+            for _ in 0..50 {
+                let mut constraint = GrammarConstraint::new(grammar.clone(), &tokenizer);
+                let mut text = vec![];
+                loop {
+                    let mut logits = (0..tokenizer.vocab_size)
+                        .map(|_| rng.gen::<f32>())
+                        .collect::<Vec<f32>>();
+                    constraint.mask_logits(&mut logits);
+                    // favour stopping as the output grows
+                    if text.len() > 30 {
+                        logits[1] += 10f32;
+                    }
+                    let next = logits
+                        .iter()
+                        .enumerate()
+                        .max_by(|a, b| a.1.total_cmp(b.1))
+                        .unwrap()
+                        .0 as u32;
+                    constraint.accept_token(next).unwrap();
+                    if tokenizer.end_tokens.contains(&next) {
+                        break;
+                    }
+                    match tokenizer.byte_fallback(next) {
+                        Some(b) => text.push(b),
+                        None => text.extend(tokenizer.vocab[next as usize].as_bytes()),
+                    }
+                }
+                let text = String::from_utf8(text).unwrap();
+                assert!(grammar.matches(&text), "{}", text);
+            }
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use crate::grammar::Grammar;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// Converts a JSON Schema into a GBNF grammar whose sentences are exactly the
+// compact JSON documents the schema accepts, so GrammarConstraint can enforce it.
+//
+// Supported: "type" (a name or a list of them), object "properties" with
+// "required", array "items", "enum", "const", "anyOf"/"oneOf" and local
+// "$ref"s into "$defs" or "definitions". Object properties are generated in
+// the order the schema declares them and no others are allowed. Without a
+// "type", "properties" implies an object and "items" an array. Anything the
+// schema leaves open, like an empty schema, accepts any JSON value.
+
+const PRIMITIVES: &str = r#"
+value   ::= object | array | string | number | boolean | null
+object  ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}"
+array   ::= "[" ws ( value ( "," ws value )* )? "]"
+string  ::= "\"" char* "\""
+char    ::= [^"\\\x00-\x1F\x7F] | "\\" ( ["\\/bfnrt] | "u" hex hex hex hex )
+hex     ::= [0-9a-fA-F]
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+number  ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+boolean ::= "true" | "false"
+null    ::= "null"
+ws      ::= " "?
+"#;
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    // the rule each "$ref" path became
+    refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    fn rule_name(name: &str) -> String {
+        name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect()
+    }
+
+    // the names a rule for name may take: its own, then with a numeric suffix
+    fn candidates(name: &str) -> impl Iterator<Item = String> {
+        let name = Converter::rule_name(name);
+        (1..).map(move |i| match i {
+            1 => name.clone(),
+            i => format!("{}-{}", name, i),
+        })
+    }
+
+    // different schemas can map to the same name, e.g. properties "a_b" and "a-b",
+    // and only share a rule when their bodies are identical too
+    fn add_rule(self: &mut Self, name: &str, body: String) -> String {
+        for candidate in Converter::candidates(name) {
+            match self.rules.iter().find(|(n, _)| *n == candidate) {
+                Some((_, b)) if *b == body => return candidate,
+                Some(_) => continue,
+                None => {
+                    self.rules.push((candidate.clone(), body));
+                    return candidate;
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    // a JSON value as a grammar literal
+    fn literal(value: &Value) -> String {
+        let json = value.to_string();
+        let escaped = json
+            .chars()
+            .map(|c| match c {
+                '"' => "\\\"".to_string(),
+                '\\' => "\\\\".to_string(),
+                '\n' => "\\n".to_string(),
+                c => c.to_string(),
+            })
+            .collect::<String>();
+        format!("\"{}\"", escaped)
+    }
+
+    fn visit(self: &mut Self, schema: &Value, name: &str) -> Result<String, String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => return Err(format!("schema '{}' accepts nothing", name)),
+            Value::Object(schema) => schema,
+            _ => return Err(format!("schema '{}' must be an object", name)),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.visit_ref(reference);
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(Converter::literal(value));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let Value::Array(values) = values else {
+                return Err(format!("'enum' of '{}' must be an array", name));
+            };
+            let alternatives = values.iter().map(Converter::literal).collect::<Vec<_>>();
+            return Ok(self.add_rule(name, alternatives.join(" | ")));
+        }
+
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let Value::Array(schemas) = schemas else {
+                return Err(format!("'anyOf' of '{}' must be an array", name));
+            };
+            let alternatives = schemas
+                .iter()
+                .enumerate()
+                .map(|(i, s)| self.visit(s, &format!("{}-{}", name, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(self.add_rule(name, alternatives.join(" | ")));
+        }
+
+        match schema.get("type") {
+            None if schema.contains_key("properties") => self.visit_object(schema, name),
+            None if schema.contains_key("items") => self.visit_type(schema, "array", name),
+            None => Ok("value".to_string()),
+            Some(Value::String(t)) => self.visit_type(schema, t, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|t| match t {
+                        Value::String(t) => self.visit_type(schema, t, &format!("{}-{}", name, t)),
+                        _ => Err(format!("'type' of '{}' must hold strings", name)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.add_rule(name, alternatives.join(" | ")))
+            }
+            Some(_) => Err(format!("'type' of '{}' must be a string or an array", name)),
+        }
+    }
+
+    fn visit_ref(self: &mut Self, reference: &Value) -> Result<String, String> {
+        let Some(path) = reference.as_str().and_then(|r| r.strip_prefix("#/")) else {
+            return Err(format!(
+                "only local references are supported, not {}",
+                reference
+            ));
+        };
+        if let Some(rule) = self.refs.get(path) {
+            return Ok(rule.clone());
+        }
+
+        let target = path
+            .split('/')
+            .try_fold(self.root, |schema, key| schema.get(key))
+            .ok_or_else(|| format!("reference {} does not resolve", reference))?;
+
+        // claim a name before visiting, the definition may refer to itself
+        let rule = Converter::candidates(&format!("ref-{}", path))
+            .find(|candidate| self.rules.iter().all(|(n, _)| n != candidate))
+            .unwrap();
+        self.rules.push((rule.clone(), String::new()));
+        self.refs.insert(path.to_string(), rule.clone());
+        let body = self.visit(target, &format!("{}-def", rule))?;
+        self.rules.iter_mut().find(|(n, _)| *n == rule).unwrap().1 = body;
+        Ok(rule)
+    }
+
+    fn visit_type(
+        self: &mut Self,
+        schema: &Map<String, Value>,
+        t: &str,
+        name: &str,
+    ) -> Result<String, String> {
+        match t {
+            "object" => self.visit_object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{}-item", name))?,
+                    None => "value".to_string(),
+                };
+                let body = format!("\"[\" ws ( {0} ( \",\" ws {0} )* )? \"]\"", item);
+                Ok(self.add_rule(name, body))
+            }
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(t.to_string()),
+            _ => Err(format!("unknown type '{}' in '{}'", t, name)),
+        }
+    }
+
+    fn visit_object(
+        self: &mut Self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, String> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok("object".to_string());
+        };
+        let Value::Object(properties) = properties else {
+            return Err(format!("'properties' of '{}' must be an object", name));
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|key| {
+                    key.as_str()
+                        .ok_or_else(|| format!("'required' of '{}' must hold strings", name))
+                })
+                .collect::<Result<Vec<&str>, String>>()?,
+            Some(_) => return Err(format!("'required' of '{}' must be an array", name)),
+            None => vec![],
+        };
+        // no properties beyond the declared ones are allowed, so these could never be written
+        if let Some(key) = required.iter().find(|&&key| !properties.contains_key(key)) {
+            return Err(format!(
+                "'{}' is required by '{}' but not in its properties",
+                key, name
+            ));
+        }
+
+        // "key": value for each property, and whether it has to be there
+        let mut members = vec![];
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{}-{}", name, key))?;
+            let member = format!(
+                "{} \":\" ws {}",
+                Converter::literal(&Value::from(key.as_str())),
+                value
+            );
+            members.push((member, required.contains(&key.as_str())));
+        }
+
+        // members from i on, once at least one has been written and each needs a comma
+        let after = |i: usize| {
+            members[i..]
+                .iter()
+                .map(|(member, required)| {
+                    if *required {
+                        format!("\",\" ws {}", member)
+                    } else {
+                        format!("( \",\" ws {} )?", member)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        // members from i on, before any has been written
+        let mut start = String::new();
+        for i in (0..members.len()).rev() {
+            let (member, required) = &members[i];
+            let first = format!("{} {}", member, after(i + 1));
+            start = if *required {
+                first
+            } else if start.is_empty() {
+                format!("( {} )?", first)
+            } else {
+                format!("( {} | {} )", first, start)
+            };
+        }
+
+        Ok(self.add_rule(name, format!("\"{{\" ws {} \"}}\"", start)))
+    }
+}
+
+pub fn schema_to_gbnf(schema: &str) -> Result<String, String> {
+    let schema: Value =
+        serde_json::from_str(schema).map_err(|e| format!("invalid schema: {}", e))?;
+    let mut converter = Converter {
+        root: &schema,
+        rules: vec![],
+        refs: HashMap::new(),
+    };
+    let root = converter.visit(&schema, "root-value")?;
+
+    let mut gbnf = format!("root ::= {}\n", root);
+    converter.rules.iter().for_each(|(name, body)| {
+        gbnf += &format!("{} ::= {}\n", name, body);
+    });
+    gbnf += PRIMITIVES;
+    Ok(gbnf)
+}
+
+impl Grammar {
+    pub fn from_json_schema(schema: &str) -> Result<Self, String> {
+        Grammar::parse(&schema_to_gbnf(schema)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraint::Constraint;
+    use crate::grammar::GrammarConstraint;
+    use crate::tokenizer::Tokenizer;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const PERSON: &str = r#"{
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "age": { "type": "integer" },
+            "height": { "type": "number" },
+            "pet": { "enum": ["cat", "dog", null] },
+            "tags": { "type": "array", "items": { "type": "string" } }
+        },
+        "required": ["name", "pet"]
+    }"#;
+
+    #[test]
+    fn test_object_with_required_fields() {
+        let grammar = Grammar::from_json_schema(PERSON).unwrap();
+        [
+            r#"{"name":"Lily","pet":"cat"}"#,
+            r#"{ "name": "Lily", "age": 3, "pet": null}"#,
+            r#"{"name":"","age":-3,"height":1.5e3,"pet":"dog","tags":["a", "b\n"]}"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        [
+            r#"{"pet":"cat"}"#,
+            r#"{"name":"Lily"}"#,
+            r#"{"name":"Lily","pet":"fish"}"#,
+            r#"{"pet":"cat","name":"Lily"}"#,
+            r#"{"name":"Lily","age":3.5,"pet":"cat"}"#,
+            r#"{"name":"Lily","pet":"cat","colour":"red"}"#,
+            r#"{"name":"Lily","pet":"cat",}"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+    }
+
+    #[test]
+    fn test_optional_only_object() {
+        let schema = r#"{"properties": {"a": {"type": "boolean"}, "b": {"const": 1}}}"#;
+        let grammar = Grammar::from_json_schema(schema).unwrap();
+        [
+            r#"{}"#,
+            r#"{"a":true}"#,
+            r#"{"b":1}"#,
+            r#"{"a":false,"b":1}"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        [r#"{,"b":1}"#, r#"{"b":2}"#, r#"{"b":1,"a":true}"#]
+            .iter()
+            .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+    }
+
+    #[test]
+    fn test_arrays_refs_and_unions() {
+        let schema = r##"{
+            "type": "array",
+            "items": { "$ref": "#/$defs/node" },
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": ["integer", "string"] },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    },
+                    "required": ["id"]
+                }
+            }
+        }"##;
+        let grammar = Grammar::from_json_schema(schema).unwrap();
+        [
+            r#"[]"#,
+            r#"[{"id":1}]"#,
+            r#"[{"id":"a","children":[{"id":2,"children":[]}]}]"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        [r#"[{"id":true}]"#, r#"[{"children":[]}]"#, r#"{"id":1}"#]
+            .iter()
+            .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+
+        let any = Grammar::from_json_schema("{}").unwrap();
+        assert!(any.matches(r#"{"x":[1,null,{"y":"z"}]}"#));
+        assert!(!any.matches(r#"{"x":}"#));
+    }
+
+    #[test]
+    fn test_schema_errors() {
+        assert!(schema_to_gbnf("{").unwrap_err().contains("invalid schema"));
+        assert!(schema_to_gbnf(r#"{"type":"date"}"#)
+            .unwrap_err()
+            .contains("date"));
+        assert!(schema_to_gbnf(r##"{"$ref":"#/$defs/x"}"##)
+            .unwrap_err()
+            .contains("resolve"));
+        assert!(schema_to_gbnf(r#"{"$ref":"other.json"}"#)
+            .unwrap_err()
+            .contains("local"));
+        assert!(
+            schema_to_gbnf(r#"{"properties":{"a":{}},"required":["a","b"]}"#)
+                .unwrap_err()
+                .contains("'b' is required")
+        );
+        assert!(schema_to_gbnf(r#"{"properties":{"a":{}},"required":[1]}"#)
+            .unwrap_err()
+            .contains("strings"));
+    }
+
+    #[test]
+    fn test_colliding_rule_names() {
+        // "a_b" and "a-b" both map to rule root-value-a-b, as do the non-ascii keys,
+        // and the $defs entries to ref---defs-x-y
+        let schema = r##"{
+            "properties": {
+                "a_b": { "type": "array", "items": { "type": "integer" } },
+                "a-b": { "type": "array", "items": { "type": "string" } },
+                "é": { "enum": [1, 2] },
+                "ü": { "enum": ["x"] },
+                "same": { "type": "array", "items": { "type": "string" } },
+                "x": { "$ref": "#/$defs/x_y" },
+                "y": { "$ref": "#/$defs/x-y" }
+            },
+            "$defs": {
+                "x_y": { "type": "array", "items": { "type": "boolean" } },
+                "x-y": { "type": "array", "items": { "type": "null" } }
+            }
+        }"##;
+        let grammar = Grammar::from_json_schema(schema).unwrap();
+        [
+            r#"{"a_b":[1],"a-b":["1"],"é":2,"ü":"x","same":["s"],"x":[true],"y":[null]}"#,
+            r#"{"a-b":[],"y":[]}"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(grammar.matches(s), "{}", s));
+        [
+            r#"{"a_b":["1"]}"#,
+            r#"{"a-b":[1]}"#,
+            r#"{"é":"x"}"#,
+            r#"{"ü":1}"#,
+            r#"{"x":[null]}"#,
+            r#"{"y":[true]}"#,
+        ]
+        .iter()
+        .for_each(|s| assert!(!grammar.matches(s), "{}", s));
+    }
+
+    #[test]
+    fn test_masked_generation_is_valid_json() {
+        let tokenizer = Tokenizer::with_pieces(&[
+            ("{\"", 1f32),
+            ("\":", 1f32),
+            ("\",\"", 1f32),
+            ("name", 1f32),
+            ("pet", 1f32),
+            ("age", 1f32),
+            ("cat", 1f32),
+            ("Lily", 1f32),
+            ("12", 1f32),
+            ("null", 1f32),
+            ("\"}", 1f32),
+        ]);
+        // no "height", serde_json refuses exponents past f64 that are still valid JSON
+        let mut schema = serde_json::from_str::<Value>(PERSON).unwrap();
+        schema["properties"]
+            .as_object_mut()
+            .unwrap()
+            .remove("height");
+        let grammar = Grammar::from_json_schema(&schema.to_string()).unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        let closers = ["\"", "}", "]", "\"}"].map(|p| tokenizer.token_lookup(p).unwrap());
+
+        for _ in 0..30 {
+            let mut constraint = GrammarConstraint::new(grammar.clone(), &tokenizer);
+            let mut text = vec![];
+            loop {
+                let mut logits = (0..tokenizer.vocab_size)
+                    .map(|_| rng.gen::<f32>())
+                    .collect::<Vec<f32>>();
+                // push towards closing things off as the output grows
+                if text.len() > 40 {
+                    closers
+                        .iter()
+                        .chain(&[1])
+                        .for_each(|&t| logits[t as usize] += 10f32);
+                }
+                constraint.mask_logits(&mut logits);
+                let next = logits
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .unwrap()
+                    .0 as u32;
+                constraint.accept_token(next).unwrap();
+                if tokenizer.end_tokens.contains(&next) {
+                    break;
+                }
+                match tokenizer.byte_fallback(next) {
+                    Some(b) => text.push(b),
+                    None => text.extend(tokenizer.vocab[next as usize].as_bytes()),
+                }
+            }
+
+            let text = String::from_utf8(text).unwrap();
+            let value: Value = serde_json::from_str(&text).expect(&text);
+            assert!(value["name"].is_string(), "{}", text);
+            assert!(
+                ["cat", "dog"].contains(&value["pet"].as_str().unwrap_or("cat")),
+                "{}",
+                text
+            );
+            assert!(value.get("age").is_none_or(Value::is_number), "{}", text);
+        }
+    }
+}
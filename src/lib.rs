@@ -0,0 +1,21 @@
+#![feature(array_chunks)]
+#![feature(slice_as_chunks)]
+#![feature(portable_simd)]
+
+pub mod beam;
+pub mod constraint;
+pub mod contrastive;
+pub mod generate;
+pub mod grammar;
+pub mod json_schema;
+mod maths;
+pub mod regex_constraint;
+pub mod sampler;
+mod sentencepiece;
+pub mod speculative;
+pub mod stop;
+pub mod tiktoken;
+pub mod tokenizer;
+mod tokenizer_json;
+pub mod transformer;
+mod utils;
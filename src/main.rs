@@ -1,77 +1,153 @@
-#![feature(array_chunks)]
-#![feature(slice_as_chunks)]
-#![feature(portable_simd)]
-
-mod maths;
-mod sampler;
-mod tokenizer;
-mod transformer;
-mod utils;
+use log::info;
 use std::io::{self, Write};
+use std::time::Instant;
 
-use sampler::{ProbIndex, Sampler};
-use tokenizer::Tokenizer;
-use transformer::Transformer;
+use rust_llm::beam::BeamSearch;
+use rust_llm::constraint::Constraint;
+use rust_llm::contrastive::ContrastiveSearch;
+use rust_llm::generate::{generate, GenerateParams, Generation};
+use rust_llm::grammar::{Grammar, GrammarConstraint};
+use rust_llm::regex_constraint::RegexConstraint;
+use rust_llm::sampler::Sampler;
+use rust_llm::speculative::Speculative;
+use rust_llm::tiktoken::Tiktoken;
+use rust_llm::tokenizer::{StreamDecoder, Tokenize, Tokenizer};
+use rust_llm::transformer::Transformer;
+use serde_json::json;
 
-fn generate(
+fn generate_speculative(
     transformer: &mut Transformer,
-    tokenizer: &Tokenizer,
-    sampler: &Sampler,
+    tokenizer: &dyn Tokenize,
+    sampler: &mut Sampler,
+    speculative: &mut Speculative,
     prompt: &str,
-    steps: i32,
-) -> Result<bool, String> {
+    params: &GenerateParams,
+) -> Result<(), String> {
     let prompt_tokens = tokenizer.encode(prompt, true, false)?;
+    print!("{}", prompt);
 
-    let mut pos = 0;
-    let mut next;
-    let mut token = prompt_tokens[0];
-    let mut prev_token = token;
+    let mut decoder = StreamDecoder::new(tokenizer, *prompt_tokens.last().unwrap());
+    let mut result = Ok(());
+    let steps = search_steps(params, &prompt_tokens);
+    speculative.generate(transformer, sampler, &prompt_tokens, steps, |token| {
+        print_token(&mut decoder, token, &mut result)
+    })?;
+    println!("{}", decoder.finish());
 
-    let mut out_tokens = vec![];
-    while pos < steps {
-        transformer.forward(token, pos);
+    result
+}
 
-        if pos < prompt_tokens.len() as i32 - 1 {
-            next = prompt_tokens[(pos + 1) as usize] as usize;
-        } else {
-            next = sampler.sample(&mut transformer.state.logits[..]);
+// prints the text a token completes, keeping the first error
+fn print_token(decoder: &mut StreamDecoder, token: u32, result: &mut Result<(), String>) {
+    match decoder.push(token) {
+        Ok(text) => print!("{}", text),
+        Err(e) => {
+            if result.is_ok() {
+                *result = Err(e);
+            }
         }
+    }
+    let _ = io::stdout().flush();
+}
 
-        if next == 1 {
-            break;
-        }
+// beam search, speculative decoding and contrastive search run their own loops,
+// which have no place for a constraint or stop strings. they refuse them rather
+// than ignore them
+fn check_search_params(
+    name: &str,
+    params: &GenerateParams,
+    constrained: bool,
+) -> Result<(), String> {
+    if constrained {
+        return Err(format!(
+            "{} does not support grammar, json_schema or regex",
+            name
+        ));
+    }
+    if !params.stop.is_empty() {
+        return Err(format!("{} does not support stop strings", name));
+    }
+    Ok(())
+}
 
-        out_tokens.push(next as u32);
+// the steps of a decoding loop, which count the prompt as well
+fn search_steps(params: &GenerateParams, prompt_tokens: &[u32]) -> i32 {
+    params
+        .max_context
+        .min(prompt_tokens.len() + params.max_new_tokens) as i32
+}
 
-        if pos > 0 {
-            let word = tokenizer.decode(token, prev_token).unwrap();
-            print!("{}", word);
-            let _ = io::stdout().flush();
-        }
+fn generate_beams(
+    transformer: &mut Transformer,
+    tokenizer: &dyn Tokenize,
+    search: &BeamSearch,
+    prompt: &str,
+    params: &GenerateParams,
+) -> Result<(), String> {
+    let prompt_tokens = tokenizer.encode(prompt, true, false)?;
+    let beams = search.search(
+        transformer,
+        &prompt_tokens,
+        search_steps(params, &prompt_tokens),
+    );
 
-        prev_token = token;
-        token = next as u32;
-        pos += 1;
+    for beam in beams {
+        let mut decoder = StreamDecoder::new(tokenizer, *prompt_tokens.last().unwrap());
+        let mut text = String::new();
+        for &token in beam.tokens.iter().filter(|&&t| !tokenizer.is_end_token(t)) {
+            text.push_str(&decoder.push(token)?);
+        }
+        text.push_str(&decoder.finish());
+        println!(
+            "[logprob {:.3}, score {:.3}] {}{}",
+            beam.logprob, beam.score, prompt, text
+        );
     }
-    println!("");
-
-    // let decoded_tokens = out_tokens
-    //     .windows(2)
-    //     .map(|pair| {
-    //         if let &[prev_token, token] = pair {
-    //             tokenizer.decode(token, prev_token).unwrap()
-    //         } else {
-    //             '\0'.to_string()
-    //         }
-    //     })
-    //     .collect::<Vec<String>>();
-    // println!("{:?}", decoded_tokens);
-
-    Ok(true)
+
+    Ok(())
+}
+
+fn generation_json(generation: &Generation, tokenizer: &dyn Tokenize, prompt: &str) -> String {
+    let piece = |token: u32| tokenizer.piece(token);
+    let tokens = generation
+        .tokens
+        .iter()
+        .map(|t| {
+            let top_logprobs = t
+                .top_logprobs
+                .iter()
+                .map(|alternative| {
+                    json!({
+                        "token": alternative.token,
+                        "piece": piece(alternative.token),
+                        "logprob": alternative.logprob,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "token": t.token,
+                "piece": t.piece,
+                "logprob": t.logprob,
+                "top_logprobs": top_logprobs,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "prompt": prompt,
+        "prompt_tokens": generation.prompt_tokens,
+        "text": generation.text,
+        "finish_reason": generation.finish_reason.to_string(),
+        "tokens": tokens,
+    })
+    .to_string()
 }
 
 fn main() -> io::Result<()> {
-    let mut transformer = transformer::Transformer::new(
+    // diagnostics go to stderr, RUST_LOG=debug for the details of loading
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut transformer = Transformer::new(
         // "assets/stories15M.bin"
         // "assets/stories42M.bin"
         // "assets/llama-3.2-1B-Instruct2.bin",
@@ -79,31 +155,228 @@ fn main() -> io::Result<()> {
     )?;
 
     let vocab_size = transformer.config.vocab_size;
-    let tokenizer = tokenizer::Tokenizer::new("assets/tokenizer.bin", vocab_size as u32)?;
+    // llama 3 checkpoints come with tiktoken ranks instead, like
+    // Some("assets/llama3_tokenizer.model"), or a byte-level tokenizer.json
+    let tiktoken_path: Option<&str> = None;
+    // special tokens written out in the prompt, like <s> or <|eot_id|>, are
+    // encoded as their ids
+    let parse_special = false;
+    let mut sentencepiece = match tiktoken_path {
+        Some(_) => None,
+        None => Some(Tokenizer::new(
+            // "assets/tokenizer.model" or "assets/tokenizer.json"
+            "assets/tokenizer.bin",
+            vocab_size as u32,
+        )?),
+    };
+    let mut tiktoken = tiktoken_path.map(Tiktoken::new).transpose()?;
+    // the sampler and the constraint masks index the model's logits by token
+    if let (Some(path), Some(tiktoken)) = (tiktoken_path, &tiktoken) {
+        if tiktoken.vocab_size != vocab_size as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has {} tokens, the model {}",
+                    path, tiktoken.vocab_size, vocab_size
+                ),
+            ));
+        }
+    }
+    sentencepiece
+        .iter_mut()
+        .for_each(|t| t.parse_special = parse_special);
+    tiktoken
+        .iter_mut()
+        .for_each(|t| t.parse_special = parse_special);
+    let tokenizer: &dyn Tokenize = match (&sentencepiece, &tiktoken) {
+        (Some(sentencepiece), _) => sentencepiece,
+        (None, Some(tiktoken)) => tiktoken,
+        (None, None) => unreachable!(),
+    };
 
     let temperature = 0f32;
+    let top_k = 0;
     let topp = 0.9f32;
-    let steps = 256;
-    let rng_state = 0;
-
-    let sampler = Sampler {
-        rng_state,
-        temperature,
-        topp,
-        vocab_size,
-        prob_index: vec![ProbIndex::default(); vocab_size as usize].into_boxed_slice(),
+    let min_p = 0f32;
+    let typical_p = 1f32;
+    let repetition_penalty = 1f32;
+    let frequency_penalty = 0f32;
+    let presence_penalty = 0f32;
+    let penalty_last_n = 64;
+    let mirostat = 0;
+    let mirostat_tau = 5f32;
+    let mirostat_eta = 0.1f32;
+    let logit_bias: &[(&str, f32)] = &[];
+    let banned: &[&str] = &[];
+    let grammar: Option<&str> = None;
+    let json_schema: Option<&str> = None;
+    let regex: Option<&str> = None;
+    // beam search replaces sampling when wider than 1
+    let beam_width = 1;
+    let length_penalty = 1f32;
+    let early_stopping = true;
+    // a smaller checkpoint proposing draft_k tokens at a time for the main one to verify
+    let draft_checkpoint: Option<&str> = None; // Some("assets/stories15M.bin");
+    let draft_k = 4;
+    // contrastive search replaces sampling when penalty_alpha > 0
+    let penalty_alpha = 0f32;
+    let contrastive_k = 4;
+    // text ending the output, matched across token boundaries and left out
+    let stop: &[&str] = &[];
+    let max_new_tokens = 256;
+    // prompt included, capped by the model's seq_len
+    let max_context = 256;
+    // report the log-probability of every token and this many alternatives
+    let logprobs: Option<usize> = None;
+    // print the result as a single json object instead of streaming text
+    let json_output = false;
+    let rng_seed = 0;
+
+    let mut sampler = Sampler {
+        top_k,
+        min_p,
+        typical_p,
+        repetition_penalty,
+        frequency_penalty,
+        presence_penalty,
+        penalty_last_n,
+        mirostat,
+        mirostat_tau,
+        mirostat_eta,
+        logit_bias: tokenizer
+            .logit_bias_from_strs(logit_bias)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        banned_strings: tokenizer.ban_list_from_strs(banned),
+        ..Sampler::new(vocab_size, temperature, topp, rng_seed)
+    };
+
+    let constraint: Option<Result<Box<dyn Constraint>, String>> =
+        match (grammar, json_schema, regex) {
+            (Some(src), _, _) => Some(
+                Grammar::parse(src)
+                    .map(|grammar| Box::new(GrammarConstraint::new(grammar, tokenizer)) as _),
+            ),
+            (None, Some(schema), _) => Some(
+                Grammar::from_json_schema(schema)
+                    .map(|grammar| Box::new(GrammarConstraint::new(grammar, tokenizer)) as _),
+            ),
+            (None, None, Some(pattern)) => {
+                Some(RegexConstraint::new(pattern, tokenizer).map(|regex| Box::new(regex) as _))
+            }
+            (None, None, None) => None,
+        };
+    let mut constraint = constraint
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // "Today I went",
+    // "Why?",
+    let prompt = "One";
+    // "One day, Lily met a Shoggoth",
+    // "\x03 abcdef 🐻\x1f",
+
+    let params = GenerateParams {
+        stop,
+        max_new_tokens,
+        max_context: max_context as usize,
+        logprobs,
     };
 
-    let _res = generate(
+    if beam_width > 1 {
+        let search = BeamSearch {
+            beam_width,
+            length_penalty,
+            early_stopping,
+            end_tokens: tokenizer.end_tokens().to_vec(),
+        };
+        check_search_params("beam search", &params, constraint.is_some())
+            .and_then(|_| generate_beams(&mut transformer, tokenizer, &search, prompt, &params))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        return Ok(());
+    }
+
+    if let Some(checkpoint) = draft_checkpoint {
+        let mut draft = Transformer::new(checkpoint)?;
+        let mut speculative = Speculative {
+            draft: &mut draft,
+            k: draft_k,
+            end_tokens: tokenizer.end_tokens().to_vec(),
+        };
+        check_search_params("speculative decoding", &params, constraint.is_some())
+            .and_then(|_| {
+                generate_speculative(
+                    &mut transformer,
+                    tokenizer,
+                    &mut sampler,
+                    &mut speculative,
+                    prompt,
+                    &params,
+                )
+            })
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        return Ok(());
+    }
+
+    if penalty_alpha > 0f32 {
+        let search = ContrastiveSearch {
+            top_k: contrastive_k,
+            alpha: penalty_alpha,
+            end_tokens: tokenizer.end_tokens().to_vec(),
+        };
+        let prompt_tokens =
+            check_search_params("contrastive search", &params, constraint.is_some())
+                .and_then(|_| tokenizer.encode(prompt, true, false))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        print!("{}", prompt);
+        let mut decoder = StreamDecoder::new(tokenizer, *prompt_tokens.last().unwrap());
+        let mut result = Ok(());
+        let steps = search_steps(&params, &prompt_tokens);
+        search.generate(&mut transformer, &prompt_tokens, steps, |token| {
+            print_token(&mut decoder, token, &mut result)
+        });
+        println!("{}", decoder.finish());
+        return result.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+
+    if json_output {
+        let generation = generate(
+            &mut transformer,
+            tokenizer,
+            &mut sampler,
+            constraint.as_deref_mut().map(|c| c as &mut dyn Constraint),
+            prompt,
+            &params,
+            |_| {},
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        println!("{}", generation_json(&generation, tokenizer, prompt));
+        return Ok(());
+    }
+
+    print!("{}", prompt);
+    let start = Instant::now();
+    let generation = generate(
         &mut transformer,
-        &tokenizer,
-        &sampler,
-        // "Today I went",
-        // "Why?",
-        "One",
-        // "One day, Lily met a Shoggoth",
-        // "\x03 abcdef 🐻\x1f",
-        steps,
+        tokenizer,
+        &mut sampler,
+        constraint.as_deref_mut().map(|c| c as &mut dyn Constraint),
+        prompt,
+        &params,
+        |text| {
+            print!("{}", text);
+            let _ = io::stdout().flush();
+        },
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    println!();
+
+    let elapsed = start.elapsed();
+    info!(
+        "finish reason: {}, {} tokens in {:.2?} ({:.2} tok/s)",
+        generation.finish_reason,
+        generation.tokens.len(),
+        elapsed,
+        generation.tokens.len() as f64 / elapsed.as_secs_f64()
     );
 
     // println!("Enter you prompt:");
@@ -111,7 +384,7 @@ fn main() -> io::Result<()> {
     // for line in stdin.lock().lines() {
     //     let p = line?;
     //     let prompt = p.as_str();
-    //     generate(&transformer, &tokenizer, &sampler, prompt, 16);
+    //     generate(&transformer, tokenizer, &sampler, prompt, 16);
     //     break;
     // }
 
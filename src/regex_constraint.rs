@@ -0,0 +1,240 @@
+use crate::constraint::{self, Constraint, TokenPiece};
+use crate::tokenizer::Tokenize;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+
+// Masks generation down to the tokens that keep the output a prefix of a match
+// of the regular expression, which must match the whole output. The pattern is
+// compiled to a byte level DFA, so multi-byte characters may arrive split over
+// byte-fallback tokens: the DFA simply sits between states until they complete.
+#[derive(Clone)]
+pub struct RegexConstraint {
+    dfa: dense::DFA<Vec<u32>>,
+    pieces: Box<[Vec<u8>]>,
+    // the pieces that are not text, whether they end the output
+    controls: Box<[Option<bool>]>,
+    state: StateID,
+}
+
+impl RegexConstraint {
+    pub fn new(pattern: &str, tokenizer: &dyn Tokenize) -> Result<Self, String> {
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .match_kind(MatchKind::All)
+                    .start_kind(StartKind::Anchored),
+            )
+            // anchored at the end as well, a match of some prefix must not keep states alive
+            .build(&format!("(?:{})$", pattern))
+            .map_err(|e| format!("invalid pattern: {}", e))?;
+        let state = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| format!("invalid pattern: {}", e))?;
+
+        let token_pieces = constraint::token_pieces(tokenizer);
+        let pieces = token_pieces
+            .iter()
+            .map(|piece| match piece {
+                TokenPiece::Text(s) => s.as_bytes().to_vec(),
+                TokenPiece::Bytes(b) => b.clone(),
+                TokenPiece::End | TokenPiece::Control => vec![],
+            })
+            .collect();
+        let controls = token_pieces
+            .iter()
+            .map(|piece| match piece {
+                TokenPiece::End => Some(true),
+                TokenPiece::Control => Some(false),
+                _ => None,
+            })
+            .collect();
+
+        Ok(RegexConstraint {
+            dfa,
+            pieces,
+            controls,
+            state,
+        })
+    }
+
+    // DFA state after the token, None if no match can follow it
+    fn step(self: &Self, token: u32) -> Option<StateID> {
+        if let Some(end) = *self.controls.get(token as usize)? {
+            return (end && self.is_complete()).then_some(self.state);
+        }
+
+        let mut state = self.state;
+        for &b in &self.pieces[token as usize] {
+            state = self.dfa.next_state(state, b);
+            if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+}
+
+impl Constraint for RegexConstraint {
+    fn mask_logits(self: &mut Self, logits: &mut [f32]) {
+        logits.iter_mut().enumerate().for_each(|(token, logit)| {
+            if self.step(token as u32).is_none() {
+                *logit = f32::NEG_INFINITY;
+            }
+        });
+    }
+
+    fn accept_token(self: &mut Self, token: u32) -> Result<(), String> {
+        self.state = self
+            .step(token)
+            .ok_or_else(|| format!("token {} cannot continue a match", token))?;
+        Ok(())
+    }
+
+    fn is_complete(self: &Self) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(self.state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Tokenizer;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use regex_automata::meta::Regex;
+
+    fn tokenizer() -> Tokenizer {
+        Tokenizer::with_pieces(&[
+            ("20", 1f32),
+            ("24", 1f32),
+            ("-0", 1f32),
+            ("-1", 1f32),
+            ("12", 1f32),
+            ("31", 1f32),
+            ("yes", 1f32),
+            ("no", 1f32),
+            (" yes", 1f32),
+            ("ye", 1f32),
+            ("né", 1f32),
+        ])
+    }
+
+    fn allowed(constraint: &mut RegexConstraint, tokenizer: &Tokenizer) -> Vec<String> {
+        let mut logits = vec![0f32; tokenizer.vocab_size as usize];
+        constraint.mask_logits(&mut logits);
+        let mut allowed = logits
+            .iter()
+            .enumerate()
+            .filter(|(_, &l)| l > f32::NEG_INFINITY)
+            .map(|(t, _)| tokenizer.vocab[t].clone())
+            .collect::<Vec<String>>();
+        allowed.sort();
+        allowed
+    }
+
+    fn feed(constraint: &mut RegexConstraint, tokenizer: &Tokenizer, pieces: &[&str]) {
+        pieces.iter().for_each(|p| {
+            let token = tokenizer.token_lookup(p).unwrap();
+            constraint.accept_token(token).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_mask_alternation() {
+        let tokenizer = tokenizer();
+        let mut constraint = RegexConstraint::new("yes|no", &tokenizer).unwrap();
+        assert_eq!(
+            allowed(&mut constraint, &tokenizer),
+            vec!["<0x6E>", "<0x79>", "n", "no", "y", "ye", "yes"]
+        );
+
+        feed(&mut constraint, &tokenizer, &["ye"]);
+        assert!(!constraint.is_complete());
+        assert_eq!(allowed(&mut constraint, &tokenizer), vec!["<0x73>", "s"]);
+
+        feed(&mut constraint, &tokenizer, &["s"]);
+        assert!(constraint.is_complete());
+        assert_eq!(
+            allowed(&mut constraint, &tokenizer),
+            vec!["\n</s>\n", "\n<s>\n"]
+        );
+    }
+
+    #[test]
+    fn test_prefix_of_longer_alternative() {
+        let tokenizer = tokenizer();
+        let mut constraint = RegexConstraint::new("a|ab", &tokenizer).unwrap();
+        feed(&mut constraint, &tokenizer, &["a"]);
+        assert!(constraint.is_complete());
+        assert!(allowed(&mut constraint, &tokenizer).contains(&"b".to_string()));
+        feed(&mut constraint, &tokenizer, &["b"]);
+        assert!(constraint.is_complete());
+    }
+
+    #[test]
+    fn test_multi_byte_characters() {
+        let tokenizer = tokenizer();
+        let mut constraint = RegexConstraint::new("n[é]", &tokenizer).unwrap();
+        assert!(allowed(&mut constraint, &tokenizer).contains(&"né".to_string()));
+
+        // é is C3 A9
+        feed(&mut constraint, &tokenizer, &["n", "<0xC3>"]);
+        assert!(!constraint.is_complete());
+        assert_eq!(allowed(&mut constraint, &tokenizer), vec!["<0xA9>"]);
+        feed(&mut constraint, &tokenizer, &["<0xA9>"]);
+        assert!(constraint.is_complete());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(RegexConstraint::new("(", &tokenizer()).is_err());
+    }
+
+    #[test]
+    fn test_masked_generation_always_matches() {
+        let tokenizer = tokenizer();
+        let mut rng = StdRng::seed_from_u64(11);
+
+        for pattern in [
+            r"\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])",
+            r"\+?\d{1,3} \(\d{3}\) \d{3}-\d{4}",
+            r" ?(yes|no)",
+            r"[a-z]+( [a-z]+)*\.",
+        ] {
+            let full_match = Regex::new(&format!("^(?:{})$", pattern)).unwrap();
+            let start = RegexConstraint::new(pattern, &tokenizer).unwrap();
+            for _ in 0..50 {
+                let mut constraint = start.clone();
+                let mut text = vec![];
+                loop {
+                    let mut logits = (0..tokenizer.vocab_size)
+                        .map(|_| rng.gen::<f32>())
+                        .collect::<Vec<f32>>();
+                    if text.len() > 20 {
+                        logits[1] += 10f32;
+                        logits[tokenizer.token_lookup(".").unwrap() as usize] += 5f32;
+                    }
+                    constraint.mask_logits(&mut logits);
+                    let next = logits
+                        .iter()
+                        .enumerate()
+                        .max_by(|a, b| a.1.total_cmp(b.1))
+                        .unwrap()
+                        .0 as u32;
+                    constraint.accept_token(next).unwrap();
+                    if tokenizer.end_tokens.contains(&next) {
+                        break;
+                    }
+                    match tokenizer.byte_fallback(next) {
+                        Some(b) => text.push(b),
+                        None => text.extend(tokenizer.vocab[next as usize].as_bytes()),
+                    }
+                }
+                let text = String::from_utf8(text).unwrap();
+                assert!(full_match.is_match(&text), "{:?} !~ {}", text, pattern);
+            }
+        }
+    }
+}
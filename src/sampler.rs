@@ -1,5 +1,6 @@
 use crate::transformer::Transformer;
-use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Default, Clone, Copy)]
 pub struct ProbIndex {
@@ -7,17 +8,103 @@ pub struct ProbIndex {
     pub index: usize,
 }
 
+// Truncation runs in a fixed order once the logits have been divided by the
+// temperature and softmaxed: top-k, top-p, min-p, then typical-p. Each stage
+// only sees the candidates the previous one kept, and its neutral value
+// (top_k = 0, topp and typical_p outside (0, 1), min_p = 0) switches it off.
+//
+// Before any of that, the raw logits are penalised for tokens seen in the last
+// penalty_last_n tokens of the history (all of it when 0): divided by
+// repetition_penalty (multiplied, when negative), then reduced by
+// frequency_penalty per occurrence and by presence_penalty once. logit_bias is
+// then added per token and banned_tokens are masked out entirely.
+//
+// mirostat (1 or 2) replaces the truncation chain with a cut-off that adapts to
+// keep the surprise of sampled tokens near mirostat_tau bits. Its running
+// mirostat_mu starts at 2 * tau and is carried from call to call until reset.
 // #[derive(Clone, Copy)]
 pub struct Sampler {
     pub temperature: f32,
+    pub top_k: usize,
     pub topp: f32,
+    pub min_p: f32,
+    pub typical_p: f32,
+    pub repetition_penalty: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub penalty_last_n: usize,
+    pub logit_bias: HashMap<u32, f32>,
+    pub banned_tokens: HashSet<u32>,
+    pub banned_strings: BannedStrings,
+    pub mirostat: u8,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    // number of top candidates v1 estimates the zipf exponent from
+    pub mirostat_m: usize,
+    pub mirostat_mu: Option<f32>,
     pub rng_state: u64,
     pub vocab_size: i32,
     pub prob_index: Box<[ProbIndex]>,
 }
 
 impl Sampler {
-    pub fn sample(self: &Self, logits: &mut [f32]) -> usize {
+    pub fn new(vocab_size: i32, temperature: f32, topp: f32, rng_seed: u64) -> Self {
+        // xorshift never leaves a zero state, so an unset seed is taken from the clock
+        let rng_state = if rng_seed == 0 {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |d| d.as_nanos() as u64)
+                | 1
+        } else {
+            rng_seed
+        };
+
+        Self {
+            temperature,
+            top_k: 0,
+            topp,
+            min_p: 0f32,
+            typical_p: 1f32,
+            repetition_penalty: 1f32,
+            frequency_penalty: 0f32,
+            presence_penalty: 0f32,
+            penalty_last_n: 64,
+            logit_bias: HashMap::new(),
+            banned_tokens: HashSet::new(),
+            banned_strings: BannedStrings::default(),
+            mirostat: 0,
+            mirostat_tau: 5f32,
+            mirostat_eta: 0.1,
+            mirostat_m: 100,
+            mirostat_mu: None,
+            rng_state,
+            vocab_size,
+            prob_index: vec![ProbIndex::default(); vocab_size as usize].into_boxed_slice(),
+        }
+    }
+
+    // clears the state carried between calls, to start a new prompt
+    pub fn reset(self: &mut Self) {
+        self.mirostat_mu = None;
+    }
+
+    // xorshift rng: https://en.wikipedia.org/wiki/Xorshift#xorshift.2A
+    fn random_u32(self: &mut Self) -> u32 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        (self.rng_state.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
+    }
+
+    // random float32 in [0, 1)
+    pub fn random_f32(self: &mut Self) -> f32 {
+        (self.random_u32() >> 8) as f32 / 16777216f32
+    }
+
+    pub fn sample(self: &mut Self, logits: &mut [f32], history: &[u32]) -> usize {
+        self.apply_penalties(logits, history);
+        self.apply_logit_bias(logits, history);
+
         if self.temperature == 0f32 {
             Sampler::sample_argmax(logits)
         } else {
@@ -26,14 +113,273 @@ impl Sampler {
             });
             Transformer::softmax(logits);
 
-            let coin: f32 = rand::thread_rng().gen();
+            let coin = self.random_f32();
 
-            if self.topp <= 0f32 || self.topp >= 1f32 {
-                Sampler::sample_mult(logits, coin)
+            let top_k = self.top_k > 0 && self.top_k < logits.len();
+            let topp = self.topp > 0f32 && self.topp < 1f32;
+            let min_p = self.min_p > 0f32;
+            let typical_p = self.typical_p > 0f32 && self.typical_p < 1f32;
+
+            if self.mirostat == 1 || self.mirostat == 2 {
+                self.sample_mirostat(logits, coin)
+            } else if top_k || min_p || typical_p {
+                self.sample_filtered(logits, coin)
+            } else if topp {
+                Sampler::sample_topp(logits, self.topp, &mut self.prob_index[..], coin)
             } else {
-                Sampler::sample_topp(logits, self.topp, &self.prob_index[..], coin)
+                Sampler::sample_mult(logits, coin)
+            }
+        }
+    }
+
+    // turns the logits into the distribution sample draws from, with every
+    // truncated token at 0 and a one-hot on the argmax at temperature 0.
+    // mirostat depends on its running state and is not covered
+    pub fn probabilities(self: &mut Self, logits: &mut [f32], history: &[u32]) {
+        self.apply_penalties(logits, history);
+        self.apply_logit_bias(logits, history);
+
+        if self.temperature == 0f32 {
+            let max_i = Sampler::sample_argmax(logits);
+            logits.fill(0f32);
+            logits[max_i] = 1f32;
+            return;
+        }
+
+        logits.iter_mut().for_each(|e| {
+            *e /= self.temperature;
+        });
+        Transformer::softmax(logits);
+
+        let candidates = &mut self.prob_index[..logits.len()];
+        logits.iter().enumerate().for_each(|(i, &prob)| {
+            candidates[i] = ProbIndex { prob, index: i };
+        });
+        let mut n = Sampler::top_k(candidates, self.top_k);
+        candidates[..n].sort_unstable_by(|a, b| b.prob.total_cmp(&a.prob));
+        n = Sampler::top_p(&candidates[..n], self.topp);
+        n = Sampler::min_p(&candidates[..n], self.min_p);
+        n = Sampler::typical_p(&mut candidates[..n], self.typical_p);
+
+        let total = candidates[..n].iter().map(|c| c.prob).sum::<f32>();
+        logits.fill(0f32);
+        candidates[..n].iter().for_each(|c| {
+            logits[c.index] = c.prob / total;
+        });
+    }
+
+    // draws from probabilities that sum to 1
+    pub fn sample_probabilities(self: &mut Self, probs: &[f32]) -> usize {
+        let coin = self.random_f32();
+        Sampler::sample_mult(probs, coin)
+    }
+
+    fn apply_penalties(self: &Self, logits: &mut [f32], history: &[u32]) {
+        if self.repetition_penalty == 1f32
+            && self.frequency_penalty == 0f32
+            && self.presence_penalty == 0f32
+        {
+            return;
+        }
+
+        let window = if self.penalty_last_n == 0 {
+            history
+        } else {
+            &history[history.len().saturating_sub(self.penalty_last_n)..]
+        };
+
+        let mut counts = HashMap::new();
+        window.iter().for_each(|&token| {
+            *counts.entry(token as usize).or_insert(0usize) += 1;
+        });
+
+        counts.iter().for_each(|(&token, &count)| {
+            if let Some(logit) = logits.get_mut(token) {
+                if *logit > 0f32 {
+                    *logit /= self.repetition_penalty;
+                } else {
+                    *logit *= self.repetition_penalty;
+                }
+                *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+            }
+        });
+    }
+
+    fn apply_logit_bias(self: &Self, logits: &mut [f32], history: &[u32]) {
+        self.logit_bias.iter().for_each(|(&token, &bias)| {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        });
+
+        self.banned_tokens.iter().for_each(|&token| {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        });
+        self.banned_strings.mask_logits(logits, history);
+    }
+
+    // runs the truncation chain over all tokens and samples from what is left
+    fn sample_filtered(self: &mut Self, probs: &[f32], coin: f32) -> usize {
+        let candidates = &mut self.prob_index[..probs.len()];
+        probs.iter().enumerate().for_each(|(i, &prob)| {
+            candidates[i] = ProbIndex { prob, index: i };
+        });
+
+        let mut n = Sampler::top_k(candidates, self.top_k);
+        candidates[..n].sort_unstable_by(|a, b| b.prob.total_cmp(&a.prob));
+        n = Sampler::top_p(&candidates[..n], self.topp);
+        n = Sampler::min_p(&candidates[..n], self.min_p);
+        n = Sampler::typical_p(&mut candidates[..n], self.typical_p);
+
+        Sampler::sample_candidates(&candidates[..n], coin)
+    }
+
+    // mirostat: https://arxiv.org/abs/2007.14966
+    // v1 keeps the top k, with k derived from mu and the zipf exponent of the
+    // distribution, v2 keeps the tokens whose surprise is at most mu. mu then
+    // moves by eta times the difference between the observed surprise and tau
+    fn sample_mirostat(self: &mut Self, probs: &[f32], coin: f32) -> usize {
+        let tau = self.mirostat_tau;
+        let mu = *self.mirostat_mu.get_or_insert(2f32 * tau);
+
+        let candidates = &mut self.prob_index[..probs.len()];
+        probs.iter().enumerate().for_each(|(i, &prob)| {
+            candidates[i] = ProbIndex { prob, index: i };
+        });
+        candidates.sort_unstable_by(|a, b| b.prob.total_cmp(&a.prob));
+
+        let n = if self.mirostat == 1 {
+            Sampler::mirostat_k(candidates, self.mirostat_m, mu)
+        } else {
+            candidates
+                .iter()
+                .position(|c| -c.prob.log2() > mu)
+                .unwrap_or(candidates.len())
+                .max(1)
+        };
+
+        let candidates = &candidates[..n];
+        let next = Sampler::sample_candidates(candidates, coin);
+        let total = candidates.iter().map(|c| c.prob).sum::<f32>();
+        let surprise = -(probs[next] / total).log2();
+        self.mirostat_mu = Some(mu - self.mirostat_eta * (surprise - tau));
+
+        next
+    }
+
+    // candidates must be sorted in descending order. estimates the zipf exponent
+    // from the first m of them and returns the k expected to give surprise mu
+    fn mirostat_k(candidates: &[ProbIndex], m: usize, mu: f32) -> usize {
+        let mut sum_ti_bi = 0f32;
+        let mut sum_ti_sq = 0f32;
+        for i in 0..m.min(candidates.len()).saturating_sub(1) {
+            if candidates[i + 1].prob <= 0f32 {
+                break;
             }
+            let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+            let b_i = (candidates[i].prob / candidates[i + 1].prob).ln();
+            sum_ti_bi += t_i * b_i;
+            sum_ti_sq += t_i * t_i;
         }
+        let s_hat = sum_ti_bi / sum_ti_sq;
+
+        let epsilon_hat = s_hat - 1f32;
+        let n = candidates.len() as f32;
+        let k = (epsilon_hat * 2f32.powf(mu) / (1f32 - n.powf(-epsilon_hat))).powf(1f32 / s_hat);
+
+        // a degenerate estimate is NaN and casts to 0
+        (k as usize).clamp(1, candidates.len())
+    }
+
+    // keeps the k most likely candidates at the front, returns how many are kept
+    fn top_k(candidates: &mut [ProbIndex], k: usize) -> usize {
+        if k == 0 || k >= candidates.len() {
+            return candidates.len();
+        }
+
+        candidates.select_nth_unstable_by(k - 1, |a, b| b.prob.total_cmp(&a.prob));
+        k
+    }
+
+    // candidates must be sorted in descending order, returns the length of the
+    // smallest prefix whose share of the remaining mass exceeds topp
+    fn top_p(candidates: &[ProbIndex], topp: f32) -> usize {
+        if topp <= 0f32 || topp >= 1f32 {
+            return candidates.len();
+        }
+
+        let total = candidates.iter().map(|c| c.prob).sum::<f32>();
+        let mut cumulative_prob = 0f32;
+        candidates
+            .iter()
+            .position(|c| {
+                cumulative_prob += c.prob;
+                cumulative_prob > topp * total
+            })
+            .map_or(candidates.len(), |i| i + 1)
+    }
+
+    // candidates must be sorted in descending order, keeps those at least
+    // min_p times as likely as the top candidate
+    fn min_p(candidates: &[ProbIndex], min_p: f32) -> usize {
+        if min_p <= 0f32 || candidates.is_empty() {
+            return candidates.len();
+        }
+
+        let threshold = candidates[0].prob * min_p;
+        candidates
+            .iter()
+            .position(|c| c.prob < threshold)
+            .unwrap_or(candidates.len())
+            .max(1)
+    }
+
+    // locally typical sampling: keeps the candidates whose surprise is closest to
+    // the entropy of the distribution, until their mass exceeds typical_p.
+    // reorders the candidates by that distance
+    fn typical_p(candidates: &mut [ProbIndex], typical_p: f32) -> usize {
+        if typical_p <= 0f32 || typical_p >= 1f32 || candidates.len() <= 1 {
+            return candidates.len();
+        }
+
+        let total = candidates.iter().map(|c| c.prob).sum::<f32>();
+        let entropy = candidates
+            .iter()
+            .filter(|c| c.prob > 0f32)
+            .map(|c| {
+                let p = c.prob / total;
+                -p * p.ln()
+            })
+            .sum::<f32>();
+        let distance = |c: &ProbIndex| (-(c.prob / total).ln() - entropy).abs();
+
+        candidates.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+
+        let mut cumulative_prob = 0f32;
+        candidates
+            .iter()
+            .position(|c| {
+                cumulative_prob += c.prob;
+                cumulative_prob > typical_p * total
+            })
+            .map_or(candidates.len(), |i| i + 1)
+    }
+
+    // sample from the (unnormalised) candidate probabilities
+    // coin is a random number in [0, 1)
+    fn sample_candidates(candidates: &[ProbIndex], coin: f32) -> usize {
+        let r = coin * candidates.iter().map(|c| c.prob).sum::<f32>();
+        let mut cdf = 0f32;
+        for candidate in candidates {
+            cdf += candidate.prob;
+            if r < cdf {
+                return candidate.index;
+            }
+        }
+
+        candidates[candidates.len() - 1].index // in case of rounding errors
     }
 
     fn sample_argmax(logits: &[f32]) -> usize {
@@ -50,11 +396,653 @@ impl Sampler {
         max_i
     }
 
-    fn sample_topp(logits: &[f32], topp: f32, prob_index: &[ProbIndex], coin: f32) -> usize {
-        0
+    // top-p sampling (or "nucleus sampling") samples from the smallest set of
+    // tokens that exceed probability topp. This way we never sample tokens that
+    // have very low probabilities and are less likely to go "off the rails".
+    // coin is a random number in [0, 1)
+    fn sample_topp(probs: &[f32], topp: f32, prob_index: &mut [ProbIndex], coin: f32) -> usize {
+        assert!(
+            !probs.is_empty(),
+            "sample_topp needs at least one probability"
+        );
+        // values smaller than (1 - topp) / (n - 1) cannot be part of the result,
+        // so filter them out as candidates before sorting
+        let cutoff = (1f32 - topp) / (probs.len() - 1).max(1) as f32;
+        let mut n0 = 0;
+        probs.iter().enumerate().for_each(|(i, &prob)| {
+            if prob >= cutoff {
+                prob_index[n0] = ProbIndex { prob, index: i };
+                n0 += 1;
+            }
+        });
+        if n0 == 0 {
+            // topp is below 1 / n and the cutoff removed everything, the nucleus is the top token
+            return Sampler::sample_argmax(probs);
+        }
+
+        // only the candidates need sorting, in descending order of probability
+        let candidates = &mut prob_index[..n0];
+        candidates.sort_unstable_by(|a, b| b.prob.total_cmp(&a.prob));
+
+        // truncate the list where the cumulative probability exceeds topp
+        let mut cumulative_prob = 0f32;
+        let mut last_idx = n0 - 1; // in case of rounding errors consider all elements
+        for (i, candidate) in candidates.iter().enumerate() {
+            cumulative_prob += candidate.prob;
+            if cumulative_prob > topp {
+                last_idx = i;
+                break;
+            }
+        }
+
+        // sample from the truncated list
+        let r = coin * cumulative_prob;
+        let mut cdf = 0f32;
+        for candidate in &candidates[..=last_idx] {
+            cdf += candidate.prob;
+            if r < cdf {
+                return candidate.index;
+            }
+        }
+
+        candidates[last_idx].index // in case of rounding errors
+    }
+
+    // sample index from probabilities (they must sum to 1!)
+    // coin is a random number in [0, 1)
+    fn sample_mult(probs: &[f32], coin: f32) -> usize {
+        assert!(
+            !probs.is_empty(),
+            "sample_mult needs at least one probability"
+        );
+        let mut cdf = 0f32;
+        for (i, &prob) in probs.iter().enumerate() {
+            cdf += prob;
+            if coin < cdf {
+                return i;
+            }
+        }
+
+        // the cdf may fall short of 1 through rounding, pick the last token with any mass
+        probs
+            .iter()
+            .rposition(|&prob| prob > 0f32)
+            .unwrap_or(probs.len() - 1)
+    }
+}
+
+// Strings the output may never contain, however the model spells them in
+// tokens. Before each draw, a token is masked if it would finish one of them:
+// its bytes hold the whole string, or the output so far ends with the first k
+// bytes of it and the token starts with the rest.
+#[derive(Default)]
+pub struct BannedStrings {
+    // the bytes each token adds to the output
+    token_bytes: Box<[Box<[u8]>]>,
+    // the banned strings, never empty
+    strings: Vec<Box<[u8]>>,
+    // per banned string, the tokens that finish it after k of its bytes, indexed by k
+    completions: Vec<Vec<Vec<u32>>>,
+}
+
+impl BannedStrings {
+    pub fn new(token_bytes: Box<[Box<[u8]>]>, strs: &[&str]) -> Self {
+        let strings = strs
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_bytes().into())
+            .collect::<Vec<Box<[u8]>>>();
+        let completions = strings
+            .iter()
+            .map(|s| {
+                let mut completions = vec![vec![]; s.len()];
+                token_bytes.iter().enumerate().for_each(|(token, bytes)| {
+                    if bytes.windows(s.len()).any(|w| w == &s[..]) {
+                        completions[0].push(token as u32);
+                    } else if !bytes.is_empty() {
+                        (1..s.len())
+                            .filter(|&k| bytes.starts_with(&s[k..]))
+                            .for_each(|k| completions[k].push(token as u32));
+                    }
+                });
+                completions
+            })
+            .collect();
+
+        Self {
+            token_bytes,
+            strings,
+            completions,
+        }
+    }
+
+    pub fn mask_logits(self: &Self, logits: &mut [f32], history: &[u32]) {
+        if self.strings.is_empty() {
+            return;
+        }
+
+        // a banned string finished by the next token starts in the last len - 1 bytes
+        let max_len = self.strings.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut tail = vec![];
+        for &token in history.iter().rev() {
+            if tail.len() >= max_len {
+                break;
+            }
+            let mut bytes = self
+                .token_bytes
+                .get(token as usize)
+                .map_or(vec![], |b| b.to_vec());
+            bytes.append(&mut tail);
+            tail = bytes;
+        }
+
+        self.strings
+            .iter()
+            .zip(&self.completions)
+            .for_each(|(s, completions)| {
+                completions
+                    .iter()
+                    .enumerate()
+                    .filter(|&(k, _)| k == 0 || tail.ends_with(&s[..k]))
+                    .flat_map(|(_, tokens)| tokens)
+                    .for_each(|&token| {
+                        if let Some(logit) = logits.get_mut(token as usize) {
+                            *logit = f32::NEG_INFINITY;
+                        }
+                    });
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transformer::Config;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // first outputs of xorshift* seeded with 42, as produced by llama2.c
+    const XORSHIFT_SEED_42: [u32; 4] = [1456360119, 3359763283, 3393612768, 4054632243];
+
+    fn softmaxed(logits: &[f32]) -> Vec<f32> {
+        let mut probs = logits.to_vec();
+        Transformer::softmax(&mut probs);
+        probs
+    }
+
+    // the distribution top-p sampling should follow: the smallest prefix of the
+    // sorted probabilities exceeding topp, renormalised
+    fn truncated_softmax(probs: &[f32], topp: f32) -> Vec<f32> {
+        let mut sorted = (0..probs.len()).collect::<Vec<usize>>();
+        sorted.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+
+        let mut expected = vec![0f32; probs.len()];
+        let mut cumulative_prob = 0f32;
+        for &i in &sorted {
+            expected[i] = probs[i];
+            cumulative_prob += probs[i];
+            if cumulative_prob > topp {
+                break;
+            }
+        }
+        expected.iter_mut().for_each(|e| *e /= cumulative_prob);
+        expected
+    }
+
+    fn empirical_topp(probs: &[f32], topp: f32, coins: impl Iterator<Item = f32>) -> Vec<f32> {
+        let mut prob_index = vec![ProbIndex::default(); probs.len()];
+        let mut counts = vec![0usize; probs.len()];
+        let mut n = 0;
+        coins.for_each(|coin| {
+            counts[Sampler::sample_topp(probs, topp, &mut prob_index, coin)] += 1;
+            n += 1;
+        });
+        counts.iter().map(|&c| c as f32 / n as f32).collect()
+    }
+
+    #[test]
+    fn test_xorshift_stream_is_pinned() {
+        let mut sampler = Sampler::new(4, 1f32, 0.9, 42);
+        let stream = (0..4).map(|_| sampler.random_u32()).collect::<Vec<u32>>();
+        assert_eq!(stream, XORSHIFT_SEED_42);
+
+        let mut sampler = Sampler::new(4, 1f32, 0.9, 42);
+        (0..1000).for_each(|_| {
+            let coin = sampler.random_f32();
+            assert!((0f32..1f32).contains(&coin));
+        });
+    }
+
+    #[test]
+    fn test_same_seed_same_generation() {
+        let config = Config {
+            dim: 16,
+            hidden_dim: 32,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 2,
+            vocab_size: 64,
+            seq_len: 32,
+        };
+
+        let generate = |seed: u64, topp: f32| {
+            let mut transformer = Transformer::random(config, 7);
+            let mut sampler = Sampler::new(config.vocab_size, 1f32, topp, seed);
+            let mut token = 1u32;
+            (0..config.seq_len)
+                .map(|pos| {
+                    transformer.forward(token, pos);
+                    token = sampler.sample(&mut transformer.state.logits, &[]) as u32;
+                    token
+                })
+                .collect::<Vec<u32>>()
+        };
+
+        for topp in [0.9f32, 1f32] {
+            assert_eq!(generate(1234, topp), generate(1234, topp));
+            assert_ne!(generate(1234, topp), generate(4321, topp));
+        }
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let mut sampler = Sampler {
+            repetition_penalty: 2f32,
+            ..Sampler::new(4, 0f32, 0f32, 1)
+        };
+        let mut logits = [4f32, -1f32, 3f32, 1f32];
+        sampler.apply_penalties(&mut logits, &[0, 1, 0]);
+        assert_eq!(logits, [2f32, -2f32, 3f32, 1f32]);
+
+        // greedy decoding moves off the repeated token
+        let mut logits = [4f32, -1f32, 3f32, 1f32];
+        assert_eq!(sampler.sample(&mut logits, &[0]), 2);
+    }
+
+    #[test]
+    fn test_frequency_and_presence_penalties() {
+        let sampler = Sampler {
+            frequency_penalty: 0.5,
+            presence_penalty: 0.25,
+            ..Sampler::new(4, 0f32, 0f32, 1)
+        };
+        let mut logits = [1f32; 4];
+        sampler.apply_penalties(&mut logits, &[2, 0, 2, 2]);
+        assert_eq!(logits, [0.25, 1f32, -0.75, 1f32]);
+    }
+
+    #[test]
+    fn test_penalty_window() {
+        let mut sampler = Sampler {
+            frequency_penalty: 1f32,
+            penalty_last_n: 2,
+            ..Sampler::new(4, 0f32, 0f32, 1)
+        };
+        let mut logits = [0f32; 4];
+        sampler.apply_penalties(&mut logits, &[0, 0, 0, 1, 2]);
+        assert_eq!(logits, [0f32, -1f32, -1f32, 0f32]);
+
+        sampler.penalty_last_n = 0;
+        let mut logits = [0f32; 4];
+        sampler.apply_penalties(&mut logits, &[0, 0, 0, 1, 2]);
+        assert_eq!(logits, [-3f32, -1f32, -1f32, 0f32]);
+    }
+
+    #[test]
+    fn test_logit_bias_and_banned_tokens() {
+        let mut sampler = Sampler {
+            logit_bias: HashMap::from([(1, 2f32), (3, -1f32)]),
+            banned_tokens: HashSet::from([0]),
+            ..Sampler::new(4, 0f32, 0f32, 1)
+        };
+        let mut logits = [5f32, 1f32, 2f32, 2.5];
+        sampler.apply_logit_bias(&mut logits, &[]);
+        assert_eq!(logits, [f32::NEG_INFINITY, 3f32, 2f32, 1.5]);
+
+        // a banned token is never drawn however likely it was
+        let mut logits = [5f32, 1f32, 2f32, 2.5];
+        assert_eq!(sampler.sample(&mut logits, &[]), 1);
+
+        sampler.temperature = 1f32;
+        sampler.topp = 0.9;
+        (0..200).for_each(|_| {
+            let mut logits = [50f32, 1f32, 2f32, 2.5];
+            assert_ne!(sampler.sample(&mut logits, &[]), 0);
+        });
+    }
+
+    #[test]
+    fn test_mult_inverse_cdf() {
+        let probs = [0.1f32, 0.2, 0.3, 0.4];
+        assert_eq!(Sampler::sample_mult(&probs, 0.0), 0);
+        assert_eq!(Sampler::sample_mult(&probs, 0.05), 0);
+        assert_eq!(Sampler::sample_mult(&probs, 0.1), 1);
+        assert_eq!(Sampler::sample_mult(&probs, 0.25), 1);
+        assert_eq!(Sampler::sample_mult(&probs, 0.35), 2);
+        assert_eq!(Sampler::sample_mult(&probs, 0.65), 3);
+        assert_eq!(Sampler::sample_mult(&probs, 0.99), 3);
+    }
+
+    #[test]
+    fn test_mult_skips_zero_probabilities() {
+        let probs = [0.0f32, 0.5, 0.0, 0.5, 0.0];
+        assert_eq!(Sampler::sample_mult(&probs, 0.0), 1);
+        assert_eq!(Sampler::sample_mult(&probs, 0.5), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one probability")]
+    fn test_mult_empty_probabilities() {
+        Sampler::sample_mult(&[], 0.5);
+    }
+
+    #[test]
+    fn test_mult_rounding_at_the_tail() {
+        // sums to slightly less than 1 in f32, a coin close to 1 walks off the end of the cdf
+        let probs = [0.01f32; 100];
+        assert!(probs.iter().sum::<f32>() < 1f32);
+        assert_eq!(Sampler::sample_mult(&probs, 0.99999994), 99);
+
+        let probs = [0.3f32, 0.3, 0.3, 0.0];
+        assert_eq!(Sampler::sample_mult(&probs, 0.95), 2);
+    }
+
+    #[test]
+    fn test_mult_seeded_coins() {
+        let probs = softmaxed(&[0.5, 1.5, -0.5, 1.0, 0.0, 2.0]);
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let n = 200_000;
+        let mut counts = vec![0usize; probs.len()];
+        (0..n).for_each(|_| counts[Sampler::sample_mult(&probs, rng.gen())] += 1);
+
+        counts.iter().zip(&probs).for_each(|(&c, &p)| {
+            assert!(
+                (c as f32 / n as f32 - p).abs() < 0.01,
+                "{:?} != {:?}",
+                counts,
+                probs
+            );
+        });
+    }
+
+    fn candidates(probs: &[f32]) -> Vec<ProbIndex> {
+        probs
+            .iter()
+            .enumerate()
+            .map(|(index, &prob)| ProbIndex { prob, index })
+            .collect()
+    }
+
+    fn sorted_candidates(probs: &[f32]) -> Vec<ProbIndex> {
+        let mut candidates = candidates(probs);
+        candidates.sort_by(|a, b| b.prob.total_cmp(&a.prob));
+        candidates
+    }
+
+    fn kept(candidates: &[ProbIndex], n: usize) -> Vec<usize> {
+        let mut kept = candidates[..n]
+            .iter()
+            .map(|c| c.index)
+            .collect::<Vec<usize>>();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn test_probabilities_match_sample() {
+        let logits = [0.5f32, 2.0, -1.0, 1.5, 0.0, 1.0];
+        let mut sampler = Sampler {
+            top_k: 4,
+            ..Sampler::new(6, 0.8, 0.8, 1)
+        };
+
+        let mut probs = logits;
+        sampler.probabilities(&mut probs, &[]);
+        assert!((probs.iter().sum::<f32>() - 1f32).abs() < 1e-6);
+
+        let n = 100_000;
+        let mut counts = [0usize; 6];
+        (0..n).for_each(|_| counts[sampler.sample(&mut logits.clone(), &[])] += 1);
+        counts.iter().zip(&probs).for_each(|(&c, &p)| {
+            assert!(
+                (c as f32 / n as f32 - p).abs() < 0.01,
+                "{:?} != {:?}",
+                counts,
+                probs
+            );
+        });
+
+        sampler.temperature = 0f32;
+        let mut probs = logits;
+        sampler.probabilities(&mut probs, &[]);
+        assert_eq!(probs, [0f32, 1f32, 0f32, 0f32, 0f32, 0f32]);
+    }
+
+    // probabilities proportional to 1 / rank^s
+    fn zipf(n: usize, s: f32) -> Vec<f32> {
+        let weights = (1..=n).map(|r| (r as f32).powf(-s)).collect::<Vec<f32>>();
+        let total = weights.iter().sum::<f32>();
+        weights.iter().map(|w| w / total).collect()
+    }
+
+    #[test]
+    fn test_mirostat_v2_step() {
+        let mut sampler = Sampler {
+            mirostat: 2,
+            mirostat_tau: 1f32,
+            mirostat_eta: 0.5,
+            ..Sampler::new(4, 1f32, 0f32, 1)
+        };
+
+        // mu starts at 2 bits, which keeps the 0.5 and the 0.25. drawing the 0.5,
+        // renormalised to 2/3, is a surprise of 0.585 bits
+        let probs = [0.125f32, 0.5, 0.125, 0.25];
+        assert_eq!(sampler.sample_mirostat(&probs, 0.5), 1);
+        let mu = 2f32 - 0.5 * ((1.5f32).log2() - 1f32);
+        assert!((sampler.mirostat_mu.unwrap() - mu).abs() < 1e-6);
+
+        // mu carries over and grew, the 0.125s are still over it
+        (0..100).for_each(|i| {
+            sampler.mirostat_mu = Some(mu);
+            assert_ne!(sampler.sample_mirostat(&probs, i as f32 / 100f32), 0);
+        });
+
+        sampler.reset();
+        assert_eq!(sampler.mirostat_mu, None);
+    }
+
+    #[test]
+    fn test_mirostat_v1_estimates_zipf_exponent() {
+        // for an exact zipf distribution the estimate is exact, and k is where
+        // the closed form of the paper puts it
+        let c = sorted_candidates(&zipf(1000, 1.2));
+        let mu = 6f32;
+        let (s, n) = (1.2f32, 1000f32);
+        let k = (0.2 * 2f32.powf(mu) / (1f32 - n.powf(-0.2))).powf(1f32 / s);
+        assert_eq!(Sampler::mirostat_k(&c, 100, mu), k as usize);
+
+        // a single candidate, or a uniform tail, cannot break it
+        assert_eq!(Sampler::mirostat_k(&c[..1], 100, mu), 1);
+        let c = sorted_candidates(&[0.25; 4]);
+        let k = Sampler::mirostat_k(&c, 100, mu);
+        assert!((1..=4).contains(&k));
+    }
+
+    #[test]
+    fn test_mirostat_converges_to_tau() {
+        let probs = zipf(2000, 1.1);
+        for mirostat in [1, 2] {
+            let tau = 4f32;
+            let mut sampler = Sampler {
+                mirostat,
+                mirostat_tau: tau,
+                ..Sampler::new(probs.len() as i32, 1f32, 0f32, 42)
+            };
+
+            // the observed surprise can be read back from the update of mu
+            let mut surprises = vec![];
+            (0..3000).for_each(|_| {
+                let mu = sampler.mirostat_mu.unwrap_or(2f32 * tau);
+                let coin = sampler.random_f32();
+                sampler.sample_mirostat(&probs, coin);
+                let next_mu = sampler.mirostat_mu.unwrap();
+                surprises.push(tau + (mu - next_mu) / sampler.mirostat_eta);
+            });
+
+            let mean = surprises[1000..].iter().sum::<f32>() / 2000f32;
+            assert!((mean - tau).abs() < 0.2, "mirostat {}: {}", mirostat, mean);
+        }
+    }
+
+    #[test]
+    fn test_top_k() {
+        let mut c = candidates(&[0.1, 0.3, 0.05, 0.25, 0.2, 0.1]);
+        let n = Sampler::top_k(&mut c, 3);
+        assert_eq!(kept(&c, n), vec![1, 3, 4]);
+
+        let mut c = candidates(&[0.5, 0.5]);
+        assert_eq!(Sampler::top_k(&mut c, 0), 2);
+        assert_eq!(Sampler::top_k(&mut c, 5), 2);
+        assert_eq!(Sampler::top_k(&mut c, 1), 1);
+    }
+
+    #[test]
+    fn test_top_p() {
+        let c = sorted_candidates(&[0.1, 0.4, 0.3, 0.2]);
+        assert_eq!(kept(&c, Sampler::top_p(&c, 0.5)), vec![1, 2]);
+        assert_eq!(kept(&c, Sampler::top_p(&c, 0.3)), vec![1]);
+        assert_eq!(kept(&c, Sampler::top_p(&c, 0.75)), vec![1, 2, 3]);
+        assert_eq!(Sampler::top_p(&c, 1f32), 4);
+
+        // measured against the mass that earlier stages left, not against 1
+        let c = sorted_candidates(&[0.2, 0.1, 0.1]);
+        assert_eq!(kept(&c, Sampler::top_p(&c, 0.6)), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_min_p() {
+        let c = sorted_candidates(&[0.05, 0.5, 0.2, 0.25]);
+        assert_eq!(kept(&c, Sampler::min_p(&c, 0.45)), vec![1, 3]);
+        assert_eq!(kept(&c, Sampler::min_p(&c, 0.4)), vec![1, 2, 3]);
+        assert_eq!(kept(&c, Sampler::min_p(&c, 1f32)), vec![1]);
+        assert_eq!(Sampler::min_p(&c, 0f32), 4);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        // entropy of [0.4, 0.3, 0.3] is 1.09 nats, the 0.3s have surprise 1.20
+        // and the 0.4 has 0.92, so the 0.3s are the typical ones
+        let mut c = sorted_candidates(&[0.4, 0.3, 0.3]);
+        let n = Sampler::typical_p(&mut c, 0.5);
+        assert_eq!(kept(&c, n), vec![1, 2]);
+
+        let mut c = sorted_candidates(&[0.4, 0.3, 0.3]);
+        let n = Sampler::typical_p(&mut c, 0.2);
+        assert_eq!(n, 1);
+        assert!(c[0].index == 1 || c[0].index == 2);
+
+        // a very peaked distribution keeps its mode
+        let mut c = sorted_candidates(&[0.97, 0.01, 0.01, 0.01]);
+        let n = Sampler::typical_p(&mut c, 0.9);
+        assert_eq!(kept(&c, n), vec![0]);
+
+        let mut c = sorted_candidates(&[0.4, 0.3, 0.3]);
+        assert_eq!(Sampler::typical_p(&mut c, 1f32), 3);
+    }
+
+    #[test]
+    fn test_filter_chain_order() {
+        // top-k keeps 3 of 5, top-p then measures against those 3 and keeps 2,
+        // and min-p finally drops the one below 0.95 of the top one
+        let probs = [0.3f32, 0.05, 0.25, 0.1, 0.27];
+        let mut sampler = Sampler {
+            top_k: 3,
+            topp: 0.6,
+            min_p: 0.95,
+            ..Sampler::new(probs.len() as i32, 1f32, 0f32, 1)
+        };
+
+        let mut counts = [0usize; 5];
+        (0..1000).for_each(|i| counts[sampler.sample_filtered(&probs, i as f32 / 1000f32)] += 1);
+        assert_eq!(counts, [1000, 0, 0, 0, 0]);
+
+        sampler.min_p = 0f32;
+        sampler.topp = 0.9;
+        let mut counts = [0usize; 5];
+        (0..1000).for_each(|i| counts[sampler.sample_filtered(&probs, i as f32 / 1000f32)] += 1);
+        assert_eq!(counts.iter().filter(|&&c| c > 0).count(), 3);
+        assert_eq!(counts[1] + counts[3], 0);
+    }
+
+    #[test]
+    fn test_topp_matches_truncated_softmax() {
+        let probs = softmaxed(&[1.0, 3.0, 0.5, 2.5, -1.0, 2.0, 0.0, 1.5]);
+        let topp = 0.9;
+        let expected = truncated_softmax(&probs, topp);
+
+        let mut rng = StdRng::seed_from_u64(1234);
+        let empirical = empirical_topp(&probs, topp, (0..200_000).map(|_| rng.gen::<f32>()));
+
+        empirical.iter().zip(&expected).for_each(|(e, x)| {
+            assert!((e - x).abs() < 0.01, "{:?} != {:?}", empirical, expected);
+        });
+    }
+
+    #[test]
+    fn test_topp_stratified_coins() {
+        let probs = softmaxed(&[0.2, -0.3, 1.7, 0.9, 1.1]);
+        let topp = 0.75;
+        let expected = truncated_softmax(&probs, topp);
+
+        // evenly spaced coins walk the whole cdf, so the counts are exact up to one bucket
+        let n = 10_000;
+        let empirical = empirical_topp(&probs, topp, (0..n).map(|i| (i as f32 + 0.5) / n as f32));
+
+        empirical.iter().zip(&expected).for_each(|(e, x)| {
+            assert!(
+                (e - x).abs() <= 2f32 / n as f32,
+                "{:?} != {:?}",
+                empirical,
+                expected
+            );
+        });
+    }
+
+    #[test]
+    fn test_topp_never_samples_outside_nucleus() {
+        let probs = softmaxed(&[5.0, 4.0, 0.0, -2.0, -3.0]);
+        let expected = truncated_softmax(&probs, 0.5);
+        let mut prob_index = vec![ProbIndex::default(); probs.len()];
+
+        for i in 0..1000 {
+            let coin = i as f32 / 1000f32;
+            let next = Sampler::sample_topp(&probs, 0.5, &mut prob_index, coin);
+            assert!(expected[next] > 0f32);
+        }
+        // the top token alone exceeds topp
+        assert_eq!(Sampler::sample_topp(&probs, 0.5, &mut prob_index, 0.999), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one probability")]
+    fn test_topp_empty_probabilities() {
+        Sampler::sample_topp(&[], 0.9, &mut [], 0.5);
+    }
+
+    #[test]
+    fn test_topp_uniform_distribution() {
+        // every probability sits exactly on the cutoff, rounding must not drop the tail
+        let probs = vec![0.25f32; 4];
+        let mut prob_index = vec![ProbIndex::default(); probs.len()];
+        let next = Sampler::sample_topp(&probs, 0.99, &mut prob_index, 0.9999);
+        assert!(next < 4);
     }
 
-    fn sample_mult(logits: &[f32], coin: f32) -> usize {
-        0
+    #[test]
+    fn test_topp_tiny_topp_falls_back_to_argmax() {
+        let probs = vec![0.4f32, 0.35, 0.25];
+        let mut prob_index = vec![ProbIndex::default(); probs.len()];
+        assert_eq!(Sampler::sample_topp(&probs, 0.01, &mut prob_index, 0.7), 0);
     }
 }
@@ -0,0 +1,495 @@
+use crate::tokenizer::{Normalizer, PieceType, Tokenizer};
+use log::warn;
+use std::str;
+use std::time::Instant;
+
+// Reads a sentencepiece tokenizer.model, the ModelProto of sentencepiece_model.proto,
+// so the Python sentencepiece package that assets/tokenizer.py needs for
+// tokenizer.bin is not needed. Unlike tokenizer.bin it keeps the type of every
+// piece and the normalizer spec.
+//
+// Supported: BPE models. The normalization rule is taken from the name in the
+// normalizer spec, identity or one of the nfkc rules, instead of from its
+// precompiled charsmap, so the nmt_ rules are applied as plain NFKC with a
+// warning. User defined pieces are found in the normalised text like sentencepiece
+// does, so only the start of the input gets the dummy prefix.
+
+// a field of a protobuf message, fixed64 fields are skipped as nothing here has one
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or("truncated varint")?;
+        *buf = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint longer than 64 bits".to_string())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if buf.len() < len {
+        return Err("truncated field".to_string());
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+// the fields of a message in order, as (field number, value)
+fn fields(mut buf: &[u8]) -> Result<Vec<(u64, Field<'_>)>, String> {
+    let mut fields = vec![];
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let field = match key & 7 {
+            0 => Field::Varint(varint(&mut buf)?),
+            1 => {
+                take(&mut buf, 8)?;
+                continue;
+            }
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                Field::Bytes(take(&mut buf, len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap())),
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+fn string(bytes: &[u8]) -> Result<&str, String> {
+    str::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+// SentencePiece: piece = 1, score = 2, type = 3
+fn piece(message: &[u8]) -> Result<(String, f32, PieceType), String> {
+    let mut piece = String::new();
+    let mut score = 0f32;
+    let mut piece_type = PieceType::Normal;
+    for (number, field) in fields(message)? {
+        match (number, field) {
+            (1, Field::Bytes(bytes)) => piece = string(bytes)?.to_string(),
+            (2, Field::Fixed32(bits)) => score = f32::from_bits(bits),
+            (3, Field::Varint(t)) => {
+                piece_type = match t {
+                    1 => PieceType::Normal,
+                    2 => PieceType::Unknown,
+                    3 => PieceType::Control,
+                    4 => PieceType::UserDefined,
+                    5 => PieceType::Unused,
+                    6 => PieceType::Byte,
+                    t => return Err(format!("unknown piece type {}", t)),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((piece, score, piece_type))
+}
+
+// an int32 id, where -1 disables the token
+fn optional_id(id: u64) -> Option<u32> {
+    u32::try_from(id as i64).ok()
+}
+
+impl Tokenizer {
+    pub fn from_model_file(path: &str) -> Result<Self, String> {
+        let start = Instant::now();
+        let model = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let tokenizer = Self::from_model(&model).map_err(|e| format!("{}: {}", path, e))?;
+        log::info!(
+            "Loaded {} tokens from {} in {:.2?}",
+            tokenizer.vocab_size,
+            path,
+            start.elapsed()
+        );
+        Ok(tokenizer)
+    }
+
+    pub fn from_model(model: &[u8]) -> Result<Self, String> {
+        let mut vocab = vec![];
+        let mut vocab_scores = vec![];
+        let mut piece_types = vec![];
+        // the proto defaults
+        let mut model_type = 1;
+        let mut byte_fallback = false;
+        let mut unk_token = 0;
+        let mut bos_token = Some(1);
+        let mut eos_token = Some(2);
+        let mut pad_token = None;
+        let mut name = "identity";
+        let mut add_dummy_prefix = true;
+        let mut remove_extra_whitespaces = true;
+        let mut escape_whitespaces = true;
+
+        for (number, field) in fields(model)? {
+            match (number, field) {
+                (1, Field::Bytes(message)) => {
+                    let (p, score, piece_type) = piece(message)?;
+                    vocab.push(p);
+                    vocab_scores.push(score);
+                    piece_types.push(piece_type);
+                }
+                // TrainerSpec
+                (2, Field::Bytes(message)) => {
+                    for (number, field) in fields(message)? {
+                        match (number, field) {
+                            (3, Field::Varint(t)) => model_type = t,
+                            (24, Field::Varint(1)) => {
+                                return Err("whitespace as suffix is not supported".to_string())
+                            }
+                            (35, Field::Varint(b)) => byte_fallback = b != 0,
+                            (40, Field::Varint(id)) => unk_token = id as u32,
+                            (41, Field::Varint(id)) => bos_token = optional_id(id),
+                            (42, Field::Varint(id)) => eos_token = optional_id(id),
+                            (43, Field::Varint(id)) => pad_token = optional_id(id),
+                            _ => {}
+                        }
+                    }
+                }
+                // NormalizerSpec
+                (3, Field::Bytes(message)) => {
+                    for (number, field) in fields(message)? {
+                        match (number, field) {
+                            (1, Field::Bytes(bytes)) => name = string(bytes)?,
+                            (3, Field::Varint(b)) => add_dummy_prefix = b != 0,
+                            (4, Field::Varint(b)) => remove_extra_whitespaces = b != 0,
+                            (5, Field::Varint(b)) => escape_whitespaces = b != 0,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if model_type != 2 {
+            let model_type = match model_type {
+                1 => "unigram".to_string(),
+                3 => "word".to_string(),
+                4 => "char".to_string(),
+                t => t.to_string(),
+            };
+            return Err(format!("{} models are not supported, only BPE", model_type));
+        }
+        for (name, id) in [
+            ("unk_id", Some(unk_token)),
+            ("bos_id", bos_token),
+            ("eos_id", eos_token),
+            ("pad_id", pad_token),
+        ] {
+            if id.is_some_and(|id| id as usize >= vocab.len()) {
+                return Err(format!("{} {} is outside the vocab", name, id.unwrap()));
+            }
+        }
+
+        if let Some(rule) = name.strip_prefix("nmt_") {
+            warn!(
+                "normalization rule {} is applied as {}, without its extra nmt rules",
+                name, rule
+            );
+        }
+        let mut normalizers = match name {
+            "identity" => vec![],
+            "nfkc" | "nmt_nfkc" => vec![Normalizer::Nfkc],
+            "nfkc_cf" | "nmt_nfkc_cf" => vec![Normalizer::Nfkc, Normalizer::Lowercase],
+            name => return Err(format!("unsupported normalization rule {}", name)),
+        };
+        if remove_extra_whitespaces {
+            normalizers.push(Normalizer::RemoveExtraWhitespaces);
+        }
+        if add_dummy_prefix {
+            normalizers.push(Normalizer::Prepend(" ".to_string()));
+        }
+        // pieces write spaces as "▁", tokenizer.bin has them back as spaces
+        if escape_whitespaces {
+            vocab = vocab.iter().map(|p| p.replace('▁', " ")).collect();
+        }
+
+        let added_tokens = piece_types
+            .iter()
+            .enumerate()
+            .filter(|(_, &t)| t == PieceType::UserDefined)
+            .map(|(id, _)| id as u32)
+            .collect();
+
+        let max_token_length = vocab.iter().map(|v| v.len()).max().unwrap_or(0);
+        let mut tokenizer =
+            Self::from_scored_pieces(vocab, vocab_scores, piece_types, max_token_length);
+        tokenizer.normalizers = normalizers;
+        tokenizer.byte_fallback = byte_fallback;
+        tokenizer.unk_token = unk_token;
+        tokenizer.added_tokens = added_tokens;
+        tokenizer.added_tokens_normalized = true;
+        // without BOS or EOS ids they are found by name, as for tokenizer.json
+        tokenizer.bos_token = bos_token.unwrap_or(tokenizer.bos_token);
+        tokenizer.eos_token = eos_token.unwrap_or(tokenizer.eos_token);
+        tokenizer.pad_token = pad_token;
+        tokenizer.reset_end_tokens();
+        Ok(tokenizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint_bytes(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    fn varint_field(number: u64, value: u64) -> Vec<u8> {
+        [varint_bytes(number << 3), varint_bytes(value)].concat()
+    }
+
+    fn bytes_field(number: u64, bytes: &[u8]) -> Vec<u8> {
+        [
+            varint_bytes(number << 3 | 2),
+            varint_bytes(bytes.len() as u64),
+            bytes.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn piece_field(piece: &str, score: f32, piece_type: u64) -> Vec<u8> {
+        let message = [
+            bytes_field(1, piece.as_bytes()),
+            varint_bytes(2 << 3 | 5),
+            score.to_le_bytes().to_vec(),
+            varint_field(3, piece_type),
+        ]
+        .concat();
+        bytes_field(1, &message)
+    }
+
+    // the layout of Tokenizer::with_pieces as a llama style BPE model, with the
+    // given normalizer spec
+    fn model(pieces: &[(&str, f32, u64)], normalizer_spec: &[u8]) -> Vec<u8> {
+        let mut model = vec![
+            piece_field("<unk>", 0.0, 2),
+            piece_field("<s>", 0.0, 3),
+            piece_field("</s>", 0.0, 3),
+        ];
+        model.extend((0..=255).map(|b| piece_field(&format!("<0x{:02X}>", b), 0.0, 6)));
+        model.extend((b' '..=b'~').map(|c| {
+            let piece = (c as char).to_string().replace(' ', "▁");
+            piece_field(&piece, 0.0, 1)
+        }));
+        model.extend(
+            pieces
+                .iter()
+                .map(|&(piece, score, piece_type)| piece_field(piece, score, piece_type)),
+        );
+        let trainer_spec = [varint_field(3, 2), varint_field(35, 1), varint_field(40, 0)].concat();
+        model.push(bytes_field(2, &trainer_spec));
+        model.push(bytes_field(3, normalizer_spec));
+        model.concat()
+    }
+
+    fn llama_normalizer_spec() -> Vec<u8> {
+        [
+            bytes_field(1, b"identity"),
+            varint_field(3, 1),
+            varint_field(4, 0),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_pieces_and_types() {
+        let tokenizer = Tokenizer::from_model(&model(
+            &[("▁a", -1.0, 1), ("<sep>", 0.0, 4)],
+            &llama_normalizer_spec(),
+        ))
+        .unwrap();
+
+        assert_eq!(tokenizer.vocab_size, 3 + 256 + 95 + 2);
+        assert_eq!(tokenizer.vocab[1], "<s>");
+        assert_eq!(tokenizer.vocab[3 + 256], " ");
+        assert_eq!(tokenizer.vocab[3 + 256 + 95], " a");
+        assert_eq!(tokenizer.vocab_scores[3 + 256 + 95], -1.0);
+        assert_eq!(tokenizer.piece_types[0], PieceType::Unknown);
+        assert_eq!(tokenizer.piece_types[2], PieceType::Control);
+        assert_eq!(tokenizer.piece_types[3 + 0x41], PieceType::Byte);
+        assert_eq!(tokenizer.piece_types[3 + 256 + 96], PieceType::UserDefined);
+        assert_eq!(tokenizer.added_tokens, vec![3 + 256 + 96]);
+        assert!(tokenizer.byte_fallback);
+        assert_eq!(
+            tokenizer.normalizers,
+            vec![Normalizer::Prepend(" ".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_matches_tokenizer_bin() {
+        let pieces = [
+            ("▁S", -1.0, 1),
+            ("▁Sh", -2.0, 1),
+            ("og", -3.0, 1),
+            ("oth", -4.0, 1),
+            ("▁Shog", -5.0, 1),
+            ("goth", -6.0, 1),
+            ("▁Shoggoth", -7.0, 1),
+            ("th", -8.0, 1),
+        ];
+        let from_model = Tokenizer::from_model(&model(&pieces, &llama_normalizer_spec())).unwrap();
+        let spaced = pieces
+            .iter()
+            .map(|&(piece, score, _)| (piece.replace('▁', " "), score))
+            .collect::<Vec<_>>();
+        let from_bin = Tokenizer::with_pieces(
+            &spaced
+                .iter()
+                .map(|(piece, score)| (piece.as_str(), *score))
+                .collect::<Vec<_>>(),
+        );
+
+        for text in ["Shoggoth", "One Shoggoth, two  Shoggoths", "é🐻", " goth "] {
+            assert_eq!(
+                from_model.encode(text, true, true).unwrap(),
+                from_bin.encode(text, true, true).unwrap(),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_user_defined_and_control_pieces() {
+        let tokenizer = Tokenizer::from_model(&model(
+            &[("ab", 0.0, 3), ("<sep>", 0.0, 4), ("b<", -1.0, 1)],
+            &llama_normalizer_spec(),
+        ))
+        .unwrap();
+        let ids = tokenizer.encode("ab<sep>b<", false, false).unwrap();
+        let pieces = ids
+            .iter()
+            .map(|&id| tokenizer.vocab[id as usize].as_str())
+            .collect::<Vec<_>>();
+
+        // a control piece is never made from the text, a user defined one always is,
+        // and the text after it has no dummy prefix
+        assert_eq!(pieces, vec![" ", "a", "b", "<sep>", "b<"]);
+
+        // the dummy prefix goes before the input even if it starts with a user
+        // defined piece, and spaces around one are kept
+        let ids = tokenizer.encode("<sep>a <sep> b", false, false).unwrap();
+        let pieces = ids
+            .iter()
+            .map(|&id| tokenizer.vocab[id as usize].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, vec![" ", "<sep>", "a", " ", "<sep>", " ", "b"]);
+        assert_eq!(tokenizer.decode_all(&ids).unwrap(), "<sep>a <sep> b");
+    }
+
+    #[test]
+    fn test_normalizer_spec() {
+        let spec = [
+            bytes_field(1, b"nmt_nfkc_cf"),
+            varint_field(3, 0),
+            bytes_field(2, &[1, 2, 3]),
+        ]
+        .concat();
+        let tokenizer = Tokenizer::from_model(&model(&[], &spec)).unwrap();
+
+        assert_eq!(
+            tokenizer.normalizers,
+            vec![
+                Normalizer::Nfkc,
+                Normalizer::Lowercase,
+                Normalizer::RemoveExtraWhitespaces
+            ]
+        );
+        let ids = tokenizer.encode("  A   ｂ ", false, false).unwrap();
+        let pieces = ids
+            .iter()
+            .map(|&id| tokenizer.vocab[id as usize].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, vec!["a", " ", "b"]);
+    }
+
+    #[test]
+    fn test_special_ids() {
+        let mut model = model(&[("<pad>", 0.0, 3)], &llama_normalizer_spec());
+        // the trainer spec comes last, the later fields win
+        let trainer_spec = [
+            varint_field(41, 2),
+            varint_field(42, 1),
+            varint_field(43, 3 + 256 + 95),
+        ]
+        .concat();
+        model.extend(bytes_field(2, &trainer_spec));
+        let tokenizer = Tokenizer::from_model(&model).unwrap();
+
+        assert_eq!(tokenizer.special_tokens["<pad>"], 3 + 256 + 95);
+        assert_eq!((tokenizer.bos_token, tokenizer.eos_token), (2, 1));
+        assert_eq!(tokenizer.pad_token, Some(3 + 256 + 95));
+        assert_eq!(tokenizer.encode("", true, true).unwrap(), vec![2, 1]);
+
+        // -1 disables a token, BOS then is the piece named <s>
+        model.extend(bytes_field(
+            2,
+            &[varint_field(41, u64::MAX), varint_field(43, u64::MAX)].concat(),
+        ));
+        let tokenizer = Tokenizer::from_model(&model).unwrap();
+        assert_eq!(tokenizer.bos_token, 1);
+        assert_eq!(tokenizer.pad_token, None);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let unigram = [
+            piece_field("<unk>", 0.0, 2),
+            bytes_field(2, &varint_field(3, 1)),
+        ]
+        .concat();
+        assert_eq!(
+            Tokenizer::from_model(&unigram).unwrap_err(),
+            "unigram models are not supported, only BPE"
+        );
+
+        let spec = bytes_field(1, b"nfkc_custom");
+        assert!(Tokenizer::from_model(&model(&[], &spec)).is_err());
+
+        let truncated = model(&[], &llama_normalizer_spec());
+        assert!(Tokenizer::from_model(&truncated[..truncated.len() - 3]).is_err());
+        assert!(Tokenizer::from_model(&[0x0a, 0xff]).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_decode_all_round_trips(text in "[ a-c▁é🐻\\x00-\\x1f]*(<sep>[ a-c]*)*") {
+            let tokenizer = Tokenizer::from_model(&model(
+                &[("▁a", -1.0, 1), ("▁▁", -2.0, 1), ("<sep>", 0.0, 4)],
+                &llama_normalizer_spec(),
+            ))
+            .unwrap();
+            let tokens = tokenizer.encode(&text, true, false).unwrap();
+            proptest::prop_assert_eq!(tokenizer.decode_all(&tokens).unwrap(), text);
+        }
+    }
+
+    // the llama 2 model through the native loader, against the ids from sentencepiece
+    #[test]
+    #[ignore = "needs the llama 2 tokenizer.model as assets/tokenizer.model and assets/tokenizer_golden.json"]
+    fn test_golden_token_ids() {
+        Tokenizer::from_model_file("assets/tokenizer.model")
+            .unwrap()
+            .assert_golden_token_ids();
+    }
+}
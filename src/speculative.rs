@@ -0,0 +1,360 @@
+use crate::sampler::Sampler;
+use crate::transformer::Transformer;
+
+// Speculative decoding: the draft model proposes up to k tokens, which the
+// target model then checks one position at a time. A proposal x drawn from the
+// draft distribution q is kept with probability min(1, p(x) / q(x)) under the
+// target distribution p, and the first rejected one is replaced by a draw from
+// max(0, p - q), so the output follows the target model exactly. At temperature
+// 0 both distributions are one-hot and this reduces to keeping proposals while
+// they equal the target's argmax.
+//
+// The kv caches are indexed by position, so rolling back a rejected proposal
+// only means moving the position back and letting the next forward overwrite it.
+pub struct Speculative<'a> {
+    pub draft: &'a mut Transformer,
+    pub k: usize,
+    // the tokenizer's end_tokens
+    pub end_tokens: Vec<u32>,
+}
+
+impl Speculative<'_> {
+    // returns the generated tokens, up to and without the end token. on_token is
+    // called with every token once it is final
+    pub fn generate(
+        self: &mut Self,
+        target: &mut Transformer,
+        sampler: &mut Sampler,
+        prompt_tokens: &[u32],
+        steps: i32,
+        mut on_token: impl FnMut(u32),
+    ) -> Result<Vec<u32>, String> {
+        if self.draft.config.vocab_size != target.config.vocab_size {
+            return Err(format!(
+                "draft vocab size {} does not match {}",
+                self.draft.config.vocab_size, target.config.vocab_size
+            ));
+        }
+        if sampler.mirostat != 0 {
+            return Err("speculative decoding does not support mirostat".to_string());
+        }
+        sampler.reset();
+
+        let steps = steps
+            .min(target.config.seq_len)
+            .min(self.draft.config.seq_len) as usize;
+        let vocab_size = target.config.vocab_size as usize;
+        let prompt_len = prompt_tokens.len();
+
+        let mut tokens = prompt_tokens.to_vec();
+        // number of tokens in each kv cache that agree with tokens
+        let mut target_pos = 0;
+        let mut draft_pos = 0;
+
+        let mut q = vec![];
+        let mut p = vec![0f32; vocab_size];
+        let mut residual = vec![0f32; vocab_size];
+
+        while tokens.len() <= steps {
+            let n = tokens.len();
+
+            // catch the draft up, then let it propose
+            while draft_pos < n - 1 {
+                self.draft.forward(tokens[draft_pos], draft_pos as i32);
+                draft_pos += 1;
+            }
+            let mut drafts = vec![];
+            q.clear();
+            while drafts.len() < self.k.min(steps - n) {
+                let last = *drafts.last().unwrap_or(&tokens[n - 1]);
+                self.draft.forward(last, draft_pos as i32);
+                draft_pos += 1;
+
+                let mut probs = self.draft.state.logits.to_vec();
+                let history = [&tokens[prompt_len..], &drafts[..]].concat();
+                sampler.probabilities(&mut probs, &history);
+                let x = sampler.sample_probabilities(&probs) as u32;
+                drafts.push(x);
+                q.push(probs);
+                if self.end_tokens.contains(&x) {
+                    break;
+                }
+            }
+
+            // verify, the target catches up on the first iteration
+            let mut accepted = 0;
+            let next = loop {
+                let last = if accepted == 0 {
+                    tokens[n - 1]
+                } else {
+                    drafts[accepted - 1]
+                };
+                while target_pos < n - 1 {
+                    target.forward(tokens[target_pos], target_pos as i32);
+                    target_pos += 1;
+                }
+                target.forward(last, target_pos as i32);
+                target_pos += 1;
+
+                p.copy_from_slice(&target.state.logits);
+                let history = [&tokens[prompt_len..], &drafts[..accepted]].concat();
+                sampler.probabilities(&mut p, &history);
+
+                if accepted == drafts.len() {
+                    break sampler.sample_probabilities(&p) as u32;
+                }
+
+                let x = drafts[accepted];
+                match Speculative::verify(sampler, &p, &q[accepted], x, &mut residual) {
+                    Some(resampled) => break resampled,
+                    None => accepted += 1,
+                }
+            };
+
+            // drop what the caches hold beyond the accepted prefix
+            draft_pos = draft_pos.min(n + accepted);
+            target_pos = n + accepted;
+
+            for token in drafts[..accepted].iter().copied().chain([next]) {
+                if self.end_tokens.contains(&token) {
+                    return Ok(tokens[prompt_len..].to_vec());
+                }
+                on_token(token);
+                tokens.push(token);
+            }
+        }
+
+        Ok(tokens[prompt_len..].to_vec())
+    }
+
+    // None when the proposal x is kept, otherwise the token replacing it
+    fn verify(
+        sampler: &mut Sampler,
+        p: &[f32],
+        q: &[f32],
+        x: u32,
+        residual: &mut [f32],
+    ) -> Option<u32> {
+        let x = x as usize;
+        if sampler.random_f32() * q[x] < p[x] {
+            return None;
+        }
+
+        residual.iter_mut().enumerate().for_each(|(i, r)| {
+            *r = (p[i] - q[i]).max(0f32);
+        });
+        let total = residual.iter().sum::<f32>();
+        if total <= 0f32 {
+            // p == q up to rounding, and the proposal was still rejected
+            return Some(sampler.sample_probabilities(p) as u32);
+        }
+        residual.iter_mut().for_each(|r| *r /= total);
+        Some(sampler.sample_probabilities(residual) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{BOS_TOKEN, EOS_TOKEN};
+    use crate::transformer::Config;
+
+    const END_TOKENS: [u32; 2] = [BOS_TOKEN, EOS_TOKEN];
+
+    const TARGET: Config = Config {
+        dim: 32,
+        hidden_dim: 64,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 2,
+        vocab_size: 16,
+        seq_len: 32,
+    };
+
+    const DRAFT: Config = Config {
+        dim: 8,
+        hidden_dim: 16,
+        n_layers: 1,
+        n_heads: 2,
+        n_kv_heads: 1,
+        vocab_size: 16,
+        seq_len: 32,
+    };
+
+    fn plain(
+        transformer: &mut Transformer,
+        sampler: &mut Sampler,
+        prompt_tokens: &[u32],
+        steps: i32,
+    ) -> Vec<u32> {
+        let mut tokens = vec![];
+        let mut token = prompt_tokens[0];
+        for pos in 0..steps {
+            transformer.forward(token, pos);
+            token = match prompt_tokens.get(pos as usize + 1) {
+                Some(&t) => t,
+                None => {
+                    let next = sampler.sample(&mut transformer.state.logits, &tokens) as u32;
+                    if END_TOKENS.contains(&next) {
+                        break;
+                    }
+                    tokens.push(next);
+                    next
+                }
+            };
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_greedy_matches_plain_decoding() {
+        let prompt_tokens = [1, 4, 9];
+        for seed in 0..8 {
+            let mut sampler = Sampler::new(16, 0f32, 0f32, 1);
+            let expected = plain(
+                &mut Transformer::random(TARGET, seed),
+                &mut sampler,
+                &prompt_tokens,
+                24,
+            );
+
+            // a draft that mostly disagrees, one that always agrees, and anything between
+            for (draft_config, draft_seed) in [(DRAFT, seed + 100), (TARGET, seed)] {
+                for k in [1, 3, 5] {
+                    let mut draft = Transformer::random(draft_config, draft_seed);
+                    let mut speculative = Speculative {
+                        draft: &mut draft,
+                        k,
+                        end_tokens: END_TOKENS.to_vec(),
+                    };
+                    let mut target = Transformer::random(TARGET, seed);
+                    let mut streamed = vec![];
+                    let tokens = speculative
+                        .generate(&mut target, &mut sampler, &prompt_tokens, 24, |t| {
+                            streamed.push(t)
+                        })
+                        .unwrap();
+                    assert_eq!(tokens, expected, "seed {} k {}", seed, k);
+                    assert_eq!(streamed, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_greedy_with_penalties_matches_plain_decoding() {
+        let prompt_tokens = [1, 2, 3];
+        let sampler = || Sampler {
+            repetition_penalty: 1.3,
+            frequency_penalty: 0.2,
+            ..Sampler::new(16, 0f32, 0f32, 1)
+        };
+        for seed in 0..4 {
+            let expected = plain(
+                &mut Transformer::random(TARGET, seed),
+                &mut sampler(),
+                &prompt_tokens,
+                30,
+            );
+            let mut draft = Transformer::random(DRAFT, seed);
+            let mut speculative = Speculative {
+                draft: &mut draft,
+                k: 4,
+                end_tokens: END_TOKENS.to_vec(),
+            };
+            let tokens = speculative
+                .generate(
+                    &mut Transformer::random(TARGET, seed),
+                    &mut sampler(),
+                    &prompt_tokens,
+                    30,
+                    |_| {},
+                )
+                .unwrap();
+            assert_eq!(tokens, expected);
+        }
+    }
+
+    #[test]
+    fn test_verify_follows_target_distribution() {
+        let p = [0.5f32, 0.1, 0.3, 0.1, 0.0];
+        let q = [0.1f32, 0.4, 0.2, 0.1, 0.2];
+        let mut sampler = Sampler::new(5, 1f32, 0f32, 42);
+        let mut residual = [0f32; 5];
+
+        let n = 200_000;
+        let mut counts = [0usize; 5];
+        (0..n).for_each(|_| {
+            let x = sampler.sample_probabilities(&q) as u32;
+            let token = Speculative::verify(&mut sampler, &p, &q, x, &mut residual).unwrap_or(x);
+            counts[token as usize] += 1;
+        });
+
+        counts.iter().zip(&p).for_each(|(&c, &p)| {
+            assert!((c as f32 / n as f32 - p).abs() < 0.01, "{:?}", counts);
+        });
+    }
+
+    #[test]
+    fn test_sampling_follows_target_distribution() {
+        // the first two generated tokens, jointly, against plain sampling
+        let prompt_tokens = [1, 5];
+        let joint = |speculative: bool| {
+            let mut target = Transformer::random(TARGET, 3);
+            let mut draft = Transformer::random(DRAFT, 4);
+            let mut sampler = Sampler::new(16, 1f32, 0f32, 7);
+            let mut counts = vec![0usize; 16 * 16];
+            for _ in 0..4_000 {
+                let tokens = if speculative {
+                    Speculative {
+                        draft: &mut draft,
+                        k: 3,
+                        end_tokens: END_TOKENS.to_vec(),
+                    }
+                    .generate(&mut target, &mut sampler, &prompt_tokens, 3, |_| {})
+                    .unwrap()
+                } else {
+                    plain(&mut target, &mut sampler, &prompt_tokens, 3)
+                };
+                if let [a, b, ..] = tokens[..] {
+                    counts[(a * 16 + b) as usize] += 1;
+                }
+            }
+            counts
+                .iter()
+                .map(|&c| c as f32 / 4_000f32)
+                .collect::<Vec<f32>>()
+        };
+
+        let (expected, empirical) = (joint(false), joint(true));
+        expected.iter().zip(&empirical).for_each(|(x, e)| {
+            assert!((x - e).abs() < 0.025, "{} != {}", x, e);
+        });
+    }
+
+    #[test]
+    fn test_mismatched_vocab() {
+        let mut draft = Transformer::random(
+            Config {
+                vocab_size: 8,
+                ..DRAFT
+            },
+            0,
+        );
+        let mut speculative = Speculative {
+            draft: &mut draft,
+            k: 2,
+            end_tokens: END_TOKENS.to_vec(),
+        };
+        let mut sampler = Sampler::new(16, 0f32, 0f32, 1);
+        assert!(speculative
+            .generate(
+                &mut Transformer::random(TARGET, 0),
+                &mut sampler,
+                &[1],
+                8,
+                |_| {}
+            )
+            .is_err());
+    }
+}
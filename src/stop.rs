@@ -0,0 +1,133 @@
+use std::fmt;
+
+// Why generate stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FinishReason {
+    // the output reached one of the stop strings
+    Stop,
+    // max_new_tokens or max_context ran out
+    Length,
+    // the model drew an end token
+    Eos,
+    // the constraint is complete and allows nothing but the end
+    Constraint,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Eos => "eos",
+            FinishReason::Constraint => "constraint",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+// Matches stop strings on the decoded text as it streams in, so a stop string
+// may be split over any number of tokens. Text that could still turn out to be
+// the start of a stop string is held back until it either completes the match,
+// and is dropped with it, or stops being a candidate.
+pub struct StopStrings<'a> {
+    stop: Vec<&'a str>,
+    pending: String,
+}
+
+impl<'a> StopStrings<'a> {
+    pub fn new(stop: &[&'a str]) -> Self {
+        StopStrings {
+            stop: stop.iter().copied().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    // takes the next piece of output and returns the text that is safe to emit,
+    // and whether a stop string was reached. after a stop nothing more is emitted
+    pub fn push(self: &mut Self, piece: &str) -> (String, bool) {
+        self.pending.push_str(piece);
+
+        let stop_at = self.stop.iter().filter_map(|s| self.pending.find(s)).min();
+        if let Some(i) = stop_at {
+            let text = self.pending[..i].to_string();
+            self.pending.clear();
+            return (text, true);
+        }
+
+        let hold_from = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| self.stop.iter().any(|s| s.starts_with(&self.pending[i..])))
+            .unwrap_or(self.pending.len());
+        let text = self.pending[..hold_from].to_string();
+        self.pending.drain(..hold_from);
+        (text, false)
+    }
+
+    // the text held back when the output ends without a stop
+    pub fn finish(self: &mut Self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(stop: &[&str], pieces: &[&str]) -> (Vec<String>, bool) {
+        let mut stop_strings = StopStrings::new(stop);
+        let mut emitted = vec![];
+        for piece in pieces {
+            let (text, stopped) = stop_strings.push(piece);
+            emitted.push(text);
+            if stopped {
+                return (emitted, true);
+            }
+        }
+        emitted.push(stop_strings.finish());
+        (emitted, false)
+    }
+
+    #[test]
+    fn test_stop_within_a_piece() {
+        let (emitted, stopped) = run(&["\n\n"], &["Once", " upon.\n\nThe", " end"]);
+        assert!(stopped);
+        assert_eq!(emitted, vec!["Once", " upon."]);
+    }
+
+    #[test]
+    fn test_stop_across_pieces() {
+        let (emitted, stopped) = run(&["The End"], &["happily", ". Th", "e", " En", "d."]);
+        assert!(stopped);
+        assert_eq!(emitted.concat(), "happily. ");
+        // nothing that might belong to the stop string leaks out early
+        assert_eq!(emitted, vec!["happily", ". ", "", "", ""]);
+    }
+
+    #[test]
+    fn test_held_text_released() {
+        let (emitted, stopped) = run(&["The End"], &["Th", "e", " cat", " Th"]);
+        assert!(!stopped);
+        assert_eq!(emitted, vec!["", "", "The cat", " ", "Th"]);
+        assert_eq!(emitted.concat(), "The cat Th");
+    }
+
+    #[test]
+    fn test_earliest_stop_wins() {
+        let (emitted, stopped) = run(&["world", "lo w"], &["hel", "lo wor", "ld"]);
+        assert!(stopped);
+        assert_eq!(emitted.concat(), "hel");
+    }
+
+    #[test]
+    fn test_multi_byte_text() {
+        let (emitted, stopped) = run(&["🐻!"], &["un ours ", "🐻", " et 🐻", "!"]);
+        assert!(stopped);
+        assert_eq!(emitted.concat(), "un ours 🐻 et ");
+
+        let (emitted, stopped) = run(&[""], &["no stop", " here"]);
+        assert!(!stopped);
+        assert_eq!(emitted.concat(), "no stop here");
+    }
+}
@@ -0,0 +1,613 @@
+use crate::tokenizer::{self, Tokenize};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fancy_regex::Regex;
+use log::info;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io;
+use std::time::Instant;
+
+// Byte-level BPE of llama 3, read from the tiktoken rank file the checkpoints ship
+// as tokenizer.model. Every line is the bytes of a token in base64 and its rank,
+// which is both the token id and the priority of the merge that makes it. Text is
+// split into pieces by PATTERN and every piece is merged up from its bytes, the
+// lowest rank first, so any text has an encoding without a byte fallback. The
+// special tokens take the ids after the ranks.
+//
+// A byte-level HuggingFace tokenizer.json loads into it as well, see
+// tokenizer_json.rs. It brings its own split pattern and special tokens, and
+// ranks merges by the pair of tokens they join, as listed, instead of by the
+// token they make.
+
+// the pre-tokenization split of llama 3
+const PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const NUM_SPECIAL_TOKENS: usize = 256;
+// the rest are <|reserved_special_token_2|> onwards
+const SPECIAL_TOKENS: [&str; 12] = [
+    "<|begin_of_text|>",
+    "<|end_of_text|>",
+    "<|reserved_special_token_0|>",
+    "<|reserved_special_token_1|>",
+    "<|finetune_right_pad_id|>",
+    "<|step_id|>",
+    "<|start_header_id|>",
+    "<|end_header_id|>",
+    "<|eom_id|>",
+    "<|eot_id|>",
+    "<|python_tag|>",
+    "<|image|>",
+];
+
+#[derive(Debug)]
+pub struct Tiktoken {
+    // the bytes of every token, the special tokens as their names
+    pub vocab: Box<[Vec<u8>]>,
+    pub vocab_size: u32,
+    // name -> id
+    pub special_tokens: HashMap<String, u32>,
+    // with parse_special, the names of special tokens in the text are encoded as
+    // their ids, otherwise as text like tiktoken's encode_ordinary
+    pub parse_special: bool,
+    pub bos_token: u32,
+    pub eos_token: u32,
+    pub pad_token: Option<u32>,
+    // <|end_of_text|>, <|eom_id|> and <|eot_id|>
+    pub end_tokens: Vec<u32>,
+    // bytes -> id, which for a rank file is also the rank of the merge making it
+    ranks: HashMap<Vec<u8>, u32>,
+    // the rank of merging two tokens, when they are listed. without them any two
+    // parts that make a token merge, by that token's rank
+    merges: Option<HashMap<(u32, u32), u32>>,
+    // a piece that is a token whole is not merged up, as with tiktoken and the
+    // ignore_merges of tokenizer.json
+    ignore_merges: bool,
+    special_ids: HashSet<u32>,
+    pattern: Regex,
+}
+
+impl Tiktoken {
+    // a rank file, or a byte-level tokenizer.json
+    pub fn new(path: &str) -> io::Result<Self> {
+        if path.ends_with(".json") {
+            return Self::from_json_file(path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        let start = Instant::now();
+        let ranks = std::fs::read_to_string(path)?;
+        let tokenizer = Self::from_ranks(&ranks)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+
+        info!(
+            "Loaded {} tokens from {} in {:.2?}",
+            tokenizer.vocab_size,
+            path,
+            start.elapsed()
+        );
+
+        Ok(tokenizer)
+    }
+
+    // the contents of a rank file, one "<base64 token> <rank>" per line
+    pub fn from_ranks(ranks: &str) -> Result<Self, String> {
+        let mut vocab = vec![];
+        for (i, line) in ranks.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("line {}: expected a token and its rank", i + 1))?;
+            let token = STANDARD
+                .decode(token)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let rank = rank
+                .parse::<usize>()
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            if rank != vocab.len() {
+                return Err(format!("line {}: rank {} is out of order", i + 1, rank));
+            }
+            vocab.push(token);
+        }
+        Self::from_vocab(vocab)
+    }
+
+    // the tokens in rank order, before the special tokens
+    pub fn from_vocab(mut vocab: Vec<Vec<u8>>) -> Result<Self, String> {
+        let num_ranks = vocab.len() as u32;
+        let special_tokens = (0..NUM_SPECIAL_TOKENS)
+            .map(|i| match SPECIAL_TOKENS.get(i) {
+                Some(name) => name.to_string(),
+                None => format!("<|reserved_special_token_{}|>", i - 10),
+            })
+            .enumerate()
+            .map(|(i, name)| (name, num_ranks + i as u32))
+            .collect::<HashMap<String, u32>>();
+        let mut names = special_tokens.iter().collect::<Vec<_>>();
+        names.sort_by_key(|&(_, &id)| id);
+        vocab.extend(names.into_iter().map(|(name, _)| name.as_bytes().to_vec()));
+
+        Self::from_parts(vocab, special_tokens, None, PATTERN, true)
+    }
+
+    // every token by id, the special tokens as their names. BOS, EOS, PAD and the
+    // other tokens ending a turn are found among the special tokens by name
+    pub(crate) fn from_parts(
+        vocab: Vec<Vec<u8>>,
+        special_tokens: HashMap<String, u32>,
+        merges: Option<HashMap<(u32, u32), u32>>,
+        pattern: &str,
+        ignore_merges: bool,
+    ) -> Result<Self, String> {
+        let special_ids = special_tokens.values().copied().collect::<HashSet<u32>>();
+        let ranks = vocab
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !special_ids.contains(&(*id as u32)))
+            .map(|(id, token)| (token.clone(), id as u32))
+            .collect::<HashMap<Vec<u8>, u32>>();
+        // merging starts from single bytes, every one of them needs a token
+        if let Some(b) = (0..=255u8).find(|&b| !ranks.contains_key(&vec![b])) {
+            return Err(format!("no token for the byte {:#04x}", b));
+        }
+
+        let named = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|&name| special_tokens.get(name).copied())
+        };
+        let eos_token = named(&tokenizer::EOS_NAMES)
+            .ok_or("no end of text among the special tokens".to_string())?;
+        let mut end_tokens = vec![eos_token];
+        end_tokens.extend(tokenizer::END_NAMES.iter().filter_map(|&n| named(&[n])));
+        end_tokens.sort_unstable();
+        end_tokens.dedup();
+
+        Ok(Tiktoken {
+            vocab_size: vocab.len() as u32,
+            vocab: vocab.into_boxed_slice(),
+            // GPT-2 starts and ends texts with <|endoftext|>
+            bos_token: named(&tokenizer::BOS_NAMES).unwrap_or(eos_token),
+            eos_token,
+            pad_token: named(&tokenizer::PAD_NAMES),
+            end_tokens,
+            special_tokens,
+            parse_special: false,
+            ranks,
+            merges,
+            ignore_merges,
+            special_ids,
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+        })
+    }
+
+    // the pieces the pattern splits text into, with any text between two matches
+    // as a piece of its own. PATTERN leaves no such gaps
+    fn split<'a>(self: &Self, text: &'a str) -> Vec<&'a str> {
+        let mut pieces = vec![];
+        let mut covered = 0;
+        self.pattern.find_iter(text).for_each(|m| {
+            // only the (?!\S) after \s+ looks around, which cannot backtrack far
+            // enough to reach the backtrack limit
+            let m = m.unwrap();
+            if m.start() > covered {
+                pieces.push(&text[covered..m.start()]);
+            }
+            pieces.push(m.as_str());
+            covered = m.end();
+        });
+        if covered < text.len() {
+            pieces.push(&text[covered..]);
+        }
+        pieces
+    }
+
+    // encodes text without special tokens, tiktoken's encode_ordinary
+    pub fn encode_ordinary(self: &Self, text: &str) -> Vec<u32> {
+        let mut tokens = vec![];
+        self.split(text).into_iter().for_each(|piece| {
+            match self
+                .ranks
+                .get(piece.as_bytes())
+                .filter(|_| self.ignore_merges)
+            {
+                Some(&id) => tokens.push(id),
+                None => tokens.extend(self.merge(piece.as_bytes())),
+            }
+        });
+        tokens
+    }
+
+    // the rank of merging the two parts of bytes split at split, if they merge
+    fn merge_rank(self: &Self, bytes: &[u8], split: usize) -> Option<u32> {
+        match &self.merges {
+            Some(merges) => {
+                let left = self.ranks[&bytes[..split]];
+                let right = self.ranks[&bytes[split..]];
+                merges.get(&(left, right)).copied()
+            }
+            None => self.ranks.get(bytes).copied(),
+        }
+    }
+
+    // the parts start as the single bytes of the piece, in a linked list over their
+    // start offsets, and every adjacent pair whose bytes are a token sits in a heap
+    // ordered by rank, then offset. as in tokenizer.rs, merged parts only ever
+    // grow to the right, so a heap entry is stale once either part has grown
+    fn merge(self: &Self, piece: &[u8]) -> Vec<u32> {
+        const NONE: usize = usize::MAX;
+        let n = piece.len();
+        // the part starting at i ends at end[i], where the next one starts
+        let mut end = (1..=n).collect::<Vec<usize>>();
+        let mut prev = (0..n).map(|i| i.wrapping_sub(1)).collect::<Vec<usize>>();
+        if n > 0 {
+            prev[0] = NONE;
+        }
+        let mut removed = vec![false; n];
+
+        let mut heap = BinaryHeap::new();
+        let candidate = |end: &[usize], left: usize| {
+            let right = end[left];
+            if right >= n {
+                return None;
+            }
+            let rank = self.merge_rank(&piece[left..end[right]], right - left)?;
+            Some(Reverse((rank, left, right, end[right])))
+        };
+        (0..n).for_each(|i| heap.extend(candidate(&end, i)));
+
+        while let Some(Reverse((_, left, right, right_end))) = heap.pop() {
+            if removed[left] || end[left] != right || end[right] != right_end {
+                continue;
+            }
+
+            end[left] = right_end;
+            removed[right] = true;
+            if right_end < n {
+                prev[right_end] = left;
+            }
+            heap.extend(candidate(&end, left));
+            if prev[left] != NONE {
+                heap.extend(candidate(&end, prev[left]));
+            }
+        }
+
+        (0..n)
+            .filter(|&i| !removed[i])
+            .map(|i| self.ranks[&piece[i..end[i]]])
+            .collect()
+    }
+}
+
+impl Tokenize for Tiktoken {
+    fn vocab_size(self: &Self) -> u32 {
+        self.vocab_size
+    }
+
+    fn encode(self: &Self, text: &str, bos: bool, eos: bool) -> Result<Vec<u32>, String> {
+        let mut tokens = vec![];
+        if bos {
+            tokens.push(self.bos_token);
+        }
+        if self.parse_special {
+            let literals = self
+                .special_tokens
+                .iter()
+                .map(|(name, &id)| (name.as_str(), id))
+                .collect::<Vec<_>>();
+            tokenizer::split_literals(text, &literals)
+                .into_iter()
+                .for_each(|(segment, _)| match segment {
+                    Ok(id) => tokens.push(id),
+                    Err(text) => tokens.extend(self.encode_ordinary(text)),
+                });
+        } else {
+            tokens.extend(self.encode_ordinary(text));
+        }
+        if eos {
+            tokens.push(self.eos_token);
+        }
+        Ok(tokens)
+    }
+
+    fn encode_fragment(self: &Self, text: &str) -> Vec<u32> {
+        self.encode_ordinary(text)
+    }
+
+    fn decode_bytes(self: &Self, token: u32, _prev_token: u32) -> Result<&[u8], String> {
+        self.vocab
+            .get(token as usize)
+            .map(|bytes| bytes.as_slice())
+            .ok_or_else(|| format!("token {} is outside the vocab", token))
+    }
+
+    // the special tokens are left out, byte-level BPE has no prefix to take off
+    fn decode_all(self: &Self, tokens: &[u32]) -> Result<String, String> {
+        let mut text = vec![];
+        for &token in tokens {
+            let bytes = self.decode_bytes(token, token)?;
+            if !self.special_ids.contains(&token) {
+                text.extend_from_slice(bytes);
+            }
+        }
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    fn piece(self: &Self, token: u32) -> String {
+        String::from_utf8_lossy(&self.vocab[token as usize]).into_owned()
+    }
+
+    // the special tokens, which only have names
+    fn is_control(self: &Self, token: u32) -> bool {
+        self.special_ids.contains(&token)
+    }
+
+    fn bos_token(self: &Self) -> u32 {
+        self.bos_token
+    }
+
+    fn eos_token(self: &Self) -> u32 {
+        self.eos_token
+    }
+
+    fn pad_token(self: &Self) -> Option<u32> {
+        self.pad_token
+    }
+
+    fn end_tokens(self: &Self) -> &[u32] {
+        &self.end_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraint::Constraint;
+    use crate::grammar::{Grammar, GrammarConstraint};
+    use crate::regex_constraint::RegexConstraint;
+    use crate::tokenizer::StreamDecoder;
+    use proptest::prelude::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // the 256 bytes, then the given tokens
+    fn tiktoken(tokens: &[&str]) -> Tiktoken {
+        let mut vocab = (0..=255u8).map(|b| vec![b]).collect::<Vec<Vec<u8>>>();
+        vocab.extend(tokens.iter().map(|t| t.as_bytes().to_vec()));
+        Tiktoken::from_vocab(vocab).unwrap()
+    }
+
+    fn pieces(tokenizer: &Tiktoken, tokens: &[u32]) -> Vec<String> {
+        tokens.iter().map(|&t| tokenizer.piece(t)).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let tokenizer = tiktoken(&[]);
+        assert_eq!(
+            tokenizer.split("Hello world's  123456 \n\n x"),
+            vec!["Hello", " world", "'s", " ", " ", "123", "456", " \n\n", " x"]
+        );
+        assert_eq!(
+            tokenizer.split("I'LL say: \"héllo\"!!\n"),
+            vec!["I", "'LL", " say", ":", " \"", "héllo", "\"!!\n"]
+        );
+        assert_eq!(tokenizer.split("a   "), vec!["a", "   "]);
+    }
+
+    #[test]
+    fn test_merge_lowest_rank_first() {
+        // "bc" ranks before "ab", so "abc" cannot start with "ab"
+        let tokenizer = tiktoken(&["bc", "ab"]);
+        let tokens = tokenizer.encode_ordinary("abcab");
+        assert_eq!(pieces(&tokenizer, &tokens), vec!["a", "bc", "ab"]);
+
+        let tokenizer = tiktoken(&["ab", "bc"]);
+        let tokens = tokenizer.encode_ordinary("abcab");
+        assert_eq!(pieces(&tokenizer, &tokens), vec!["ab", "c", "ab"]);
+
+        // any two parts that make a token merge, not only listed pairs
+        let tokenizer = tiktoken(&["ab", "bc", "abc"]);
+        let tokens = tokenizer.encode_ordinary("abcab");
+        assert_eq!(pieces(&tokenizer, &tokens), vec!["abc", "ab"]);
+
+        // merges stay within the pieces of the split
+        let tokenizer = tiktoken(&["a ", " b"]);
+        assert_eq!(
+            pieces(&tokenizer, &tokenizer.encode_ordinary("a b")),
+            vec!["a", " b"]
+        );
+    }
+
+    // tiktoken's byte_pair_merge, rescanning every pair after each merge
+    fn merge_reference(tokenizer: &Tiktoken, piece: &[u8]) -> Vec<u32> {
+        let mut parts = (0..piece.len()).map(|i| i..i + 1).collect::<Vec<_>>();
+        loop {
+            let best = parts
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, w)| Some((*tokenizer.ranks.get(&piece[w[0].start..w[1].end])?, i)))
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            parts[i].end = parts[i + 1].end;
+            parts.remove(i + 1);
+        }
+        parts
+            .into_iter()
+            .map(|p| tokenizer.ranks[&piece[p]])
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_matches_reference() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let alphabet = ["a", "b", "c", "é"];
+        for _ in 0..20 {
+            let mut tokens = (0..150)
+                .map(|_| {
+                    (0..rng.gen_range(2..6))
+                        .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                        .collect::<String>()
+                })
+                .collect::<Vec<String>>();
+            // the bytes of é on their own, so parts of characters merge too
+            tokens.push(
+                "\u{e9}".as_bytes()[..1]
+                    .iter()
+                    .map(|&b| b as char)
+                    .collect(),
+            );
+            tokens.sort();
+            tokens.dedup();
+            let tokens = tokens.iter().map(|t| t.as_str()).collect::<Vec<&str>>();
+            let tokenizer = tiktoken(&tokens);
+
+            for len in [1, 2, 7, 40, 300] {
+                let piece = (0..len)
+                    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                    .collect::<String>();
+                assert_eq!(
+                    tokenizer.merge(piece.as_bytes()),
+                    merge_reference(&tokenizer, piece.as_bytes()),
+                    "{:?}",
+                    piece
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let tokenizer = tiktoken(&["ab"]);
+        assert_eq!(tokenizer.vocab_size, 257 + 256);
+        assert_eq!(tokenizer.bos_token, 257);
+        assert_eq!(tokenizer.special_tokens["<|eot_id|>"], 257 + 9);
+        assert_eq!(
+            tokenizer.special_tokens["<|reserved_special_token_2|>"],
+            257 + 12
+        );
+        assert_eq!(
+            tokenizer.special_tokens["<|reserved_special_token_245|>"],
+            257 + 255
+        );
+        assert_eq!(tokenizer.end_tokens, vec![258, 257 + 8, 257 + 9]);
+
+        let tokens = tokenizer.encode("ab", true, true).unwrap();
+        assert_eq!(tokens, vec![257, 256, 258]);
+        assert_eq!(tokenizer.piece(tokens[2]), "<|end_of_text|>");
+
+        assert_eq!(tokenizer.pad_token, Some(257 + 4));
+
+        // special tokens in the text are plain text unless parsed
+        let text = "<|start_header_id|>ab<|end_header_id|>";
+        let tokens = tokenizer.encode(text, false, false).unwrap();
+        assert!(tokens.iter().all(|&t| t < 257));
+        let mut tokenizer = tokenizer;
+        tokenizer.parse_special = true;
+        assert_eq!(
+            tokenizer.encode(text, false, false).unwrap(),
+            vec![257 + 6, 256, 257 + 7]
+        );
+    }
+
+    #[test]
+    fn test_stream_decoding() {
+        let tokenizer = tiktoken(&["\u{1f43b}"]);
+        let bear = tokenizer.encode_ordinary("\u{1f43b}");
+        assert_eq!(bear, vec![256]);
+
+        // é as two byte tokens comes out whole
+        let tokens = tokenizer.encode("é\u{1f43b} !", true, false).unwrap();
+        assert_eq!(tokens.len(), 6);
+        let mut decoder = StreamDecoder::new(&tokenizer, tokens[0]);
+        let texts = tokens[1..]
+            .iter()
+            .map(|&t| decoder.push(t).unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(texts, vec!["", "é", "\u{1f43b}", " ", "!"]);
+    }
+
+    // the tokens a constraint leaves open, as their bytes
+    fn allowed(constraint: &mut dyn Constraint, tokenizer: &Tiktoken) -> Vec<Vec<u8>> {
+        let mut logits = vec![0f32; tokenizer.vocab_size as usize];
+        constraint.mask_logits(&mut logits);
+        let mut allowed = (0..tokenizer.vocab_size)
+            .filter(|&t| logits[t as usize] > f32::NEG_INFINITY)
+            .map(|t| tokenizer.vocab[t as usize].clone())
+            .collect::<Vec<Vec<u8>>>();
+        allowed.sort();
+        allowed
+    }
+
+    #[test]
+    fn test_constraints_over_byte_level_tokens() {
+        // "é" is c3 a9 and "ü" c3 bc, the last two tokens split é across them
+        let mut vocab = (0..=255u8).map(|b| vec![b]).collect::<Vec<Vec<u8>>>();
+        vocab.extend([
+            b"\xc3\xa9".to_vec(),
+            b"ab".to_vec(),
+            b"x\xc3".to_vec(),
+            b"\xa9b".to_vec(),
+        ]);
+        let tokenizer = Tiktoken::from_vocab(vocab).unwrap();
+        let token = |bytes: &[u8]| tokenizer.ranks[bytes];
+        let grammar = Grammar::parse(r#"root ::= "x"? "é" [ab]+ | "ü""#).unwrap();
+        let mut constraint = GrammarConstraint::new(grammar, &tokenizer);
+
+        let expected: [&[u8]; 4] = [b"x", b"x\xc3", b"\xc3", b"\xc3\xa9"];
+        assert_eq!(allowed(&mut constraint, &tokenizer), expected);
+        constraint.accept_token(token(b"x\xc3")).unwrap();
+        // ü cannot follow x
+        let expected: [&[u8]; 2] = [b"\xa9", b"\xa9b"];
+        assert_eq!(allowed(&mut constraint, &tokenizer), expected);
+        constraint.accept_token(token(b"\xa9b")).unwrap();
+        assert!(constraint.is_complete());
+        // the end tokens are open once the output is complete, the other specials never
+        let open = allowed(&mut constraint, &tokenizer);
+        assert!(open.contains(&b"<|eot_id|>".to_vec()));
+        assert!(!open.contains(&b"<|begin_of_text|>".to_vec()));
+
+        let mut constraint = RegexConstraint::new("é+", &tokenizer).unwrap();
+        let expected: [&[u8]; 2] = [b"\xc3", b"\xc3\xa9"];
+        assert_eq!(allowed(&mut constraint, &tokenizer), expected);
+        constraint.accept_token(token(b"\xc3")).unwrap();
+        assert_eq!(allowed(&mut constraint, &tokenizer), [b"\xa9".to_vec()]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_decode_all_round_trips(text in any::<String>(), special in any::<bool>()) {
+            let tokenizer = tiktoken(&["ab", " a", "<|", "\u{301}"]);
+            let text = if special { format!("<|eot_id|>{}", text) } else { text };
+            let tokens = tokenizer.encode(&text, true, true).unwrap();
+            prop_assert_eq!(tokenizer.decode_all(&tokens).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_from_ranks() {
+        let ranks = (0..=255u8)
+            .map(|b| format!("{} {}", STANDARD.encode([b]), b))
+            .chain([format!("{} 256", STANDARD.encode("hi"))])
+            .collect::<Vec<String>>()
+            .join("\n");
+        let tokenizer = Tiktoken::from_ranks(&ranks).unwrap();
+        assert_eq!(tokenizer.encode_ordinary("hi"), vec![256]);
+
+        assert!(Tiktoken::from_ranks(&ranks.replace(" 256", " 300")).is_err());
+        assert!(Tiktoken::from_ranks(&format!("{} 0", STANDARD.encode("hi"))).is_err());
+    }
+
+    // ids as the llama 3 tokenizer gives them
+    #[test]
+    #[ignore = "needs the llama 3 tokenizer.model as assets/llama3_tokenizer.model"]
+    fn test_llama3_token_ids() {
+        let tokenizer = Tiktoken::new("assets/llama3_tokenizer.model").unwrap();
+        assert_eq!(tokenizer.vocab_size, 128256);
+        assert_eq!(
+            tokenizer.encode("Hello world!", true, false).unwrap(),
+            vec![128000, 9906, 1917, 0]
+        );
+    }
+}
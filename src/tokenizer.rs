@@ -1,8 +1,16 @@
+use crate::sampler::BannedStrings;
 use crate::utils;
 use core::{f32, str};
+use log::{debug, info};
+use regex_automata::meta::Regex;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
 use std::io;
-use unicode_segmentation::UnicodeSegmentation;
+use std::sync::LazyLock;
+use std::time::Instant;
+use unicode_normalization::char::{canonical_combining_class, compose};
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Debug)]
 pub struct Tokenizer {
@@ -12,13 +20,169 @@ pub struct Tokenizer {
     pub vocab_sorted: Box<[u32]>,
     pub max_token_length: usize,
     pub byte_pieces: [u8; 256],
+    pub piece_types: Box<[PieceType]>,
+    // applied in order to the text before it is split into words
+    pub normalizers: Vec<Normalizer>,
+    // split the normalised text into words that are merged separately
+    pub pre_tokenizers: Vec<PreTokenizer>,
+    // code points outside the vocab become their <0xXX> byte tokens, or unk_token
+    pub byte_fallback: bool,
+    pub unk_token: u32,
+    // a run of unknown code points becomes a single unk_token
+    pub fuse_unk: bool,
+    // tokens matched literally in the text before anything else, as single ids
+    pub added_tokens: Vec<u32>,
+    // sentencepiece finds its user defined pieces in the normalised text, so the
+    // normalizers see the input as a whole and the dummy prefix only goes in front
+    // of it. otherwise, as in tokenizer.json, added tokens are split off first and
+    // the text between them is normalised piece by piece
+    pub added_tokens_normalized: bool,
+    // name -> id of the control tokens, e.g. <s>
+    pub special_tokens: HashMap<String, u32>,
+    // with parse_special, the names of special tokens in the text are matched like
+    // added tokens, rather than encoded as text
+    pub parse_special: bool,
+    pub bos_token: u32,
+    pub eos_token: u32,
+    pub pad_token: Option<u32>,
+    // the tokens that end the model's output
+    pub end_tokens: Vec<u32>,
+    // (left, right) -> the token whose piece is the two pieces joined, and the
+    // priority of the merge
+    merges: HashMap<(u32, u32), (u32, f32)>,
+    byte_tokens: [Option<u32>; 256],
 }
 
+// the kinds of sentencepiece pieces. only normal pieces are made by merges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceType {
+    Normal,
+    Unknown,
+    // BOS, EOS and the like, never part of the text
+    Control,
+    // matched whole in the text, like added tokens
+    UserDefined,
+    Unused,
+    // <0xXX>, one byte of a code point outside the vocab
+    Byte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Normalizer {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+    Lowercase,
+    // the dummy prefix, added to text at the start of the input and, unless
+    // added_tokens_normalized, after every added token
+    Prepend(String),
+    Replace(String, String),
+    // whitespace on either side
+    Strip { left: bool, right: bool },
+    // leading and trailing spaces, and all but one of the spaces in a row
+    RemoveExtraWhitespaces,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrependScheme {
+    Always,
+    // only before the first text of the input, not after added tokens
+    First,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreTokenizer {
+    // sentencepiece style words with their leading space. prepends a space where
+    // the text does not start with one already, and with split every space
+    // starts a new word
+    Metaspace { prepend: PrependScheme, split: bool },
+    // runs of word characters and runs of punctuation, whitespace is dropped
+    Whitespace,
+    // runs of anything but whitespace
+    WhitespaceSplit,
+    // digits apart from the rest, each on its own when individual
+    Digits { individual: bool },
+}
+
+// the ids of llama2.c's vocab, which sentencepiece llama models share
+pub const UNK_TOKEN: u32 = 0;
+pub const BOS_TOKEN: u32 = 1;
+pub const EOS_TOKEN: u32 = 2;
+
+// names of BOS, EOS and PAD in the vocabs of the usual checkpoints, and the other
+// tokens ending a chat turn
+pub(crate) const BOS_NAMES: [&str; 3] = ["<s>", "<|begin_of_text|>", "<bos>"];
+pub(crate) const EOS_NAMES: [&str; 4] = ["</s>", "<|end_of_text|>", "<eos>", "<|endoftext|>"];
+pub(crate) const PAD_NAMES: [&str; 2] = ["<pad>", "<|finetune_right_pad_id|>"];
+pub(crate) const END_NAMES: [&str; 3] = ["<|eot_id|>", "<|eom_id|>", "<end_of_turn>"];
+
+// the (start, end) byte range of a text that a token covers
+pub type Span = (usize, usize);
+
+static WORDS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\w+|[^\w\s]+").unwrap());
+
+// a pair of adjacent tokens that can merge, the best score first and the
+// leftmost among equal scores
+struct Merge {
+    score: f32,
+    left: usize,
+    right: usize,
+    pair: (u32, u32),
+    id: u32,
+}
+
+impl Ord for Merge {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+impl PartialOrd for Merge {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Merge {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Merge {}
+
 impl Tokenizer {
+    // a llama2.c tokenizer.bin, a sentencepiece tokenizer.model or a HuggingFace
+    // tokenizer.json
     pub fn new(tokenizer_file_path: &str, vocab_size: u32) -> io::Result<Self> {
+        let loaded = if tokenizer_file_path.ends_with(".json") {
+            Some(Self::from_json_file(tokenizer_file_path))
+        } else if tokenizer_file_path.ends_with(".model") {
+            Some(Self::from_model_file(tokenizer_file_path))
+        } else {
+            None
+        };
+        if let Some(tokenizer) = loaded {
+            let tokenizer = tokenizer.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if tokenizer.vocab_size > vocab_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} has {} tokens, more than the model's {}",
+                        tokenizer_file_path, tokenizer.vocab_size, vocab_size
+                    ),
+                ));
+            }
+            return Ok(tokenizer);
+        }
+
+        let start = Instant::now();
         let mut tokenizer_file = File::open(tokenizer_file_path)?;
 
-        let byte_pieces: [u8; 256] = (0..=255).collect::<Vec<u8>>().try_into().unwrap();
         let max_token_length = utils::read_variable_length_data::<u32>(&mut tokenizer_file, 1)?[0];
 
         let (vocab_scores, vocab): (Vec<_>, Vec<_>) = (0..vocab_size)
@@ -35,22 +199,163 @@ impl Tokenizer {
             })
             .unzip();
 
+        info!(
+            "Loaded {} tokens from {} in {:.2?}",
+            vocab_size,
+            tokenizer_file_path,
+            start.elapsed()
+        );
+
+        Ok(Self::from_vocab(
+            vocab,
+            vocab_scores,
+            max_token_length as usize,
+        ))
+    }
+
+    // a llama2.c vocab: <unk>, BOS and EOS, then the byte tokens among the
+    // others. text gets sentencepiece's dummy prefix space. the vocab writes BOS
+    // and EOS as "\n<s>\n" and "\n</s>\n", so they are named here
+    pub fn from_vocab(vocab: Vec<String>, vocab_scores: Vec<f32>, max_token_length: usize) -> Self {
+        let piece_types = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| match id {
+                0 => PieceType::Unknown,
+                1 | 2 => PieceType::Control,
+                _ if is_byte_piece(piece) => PieceType::Byte,
+                _ => PieceType::Normal,
+            })
+            .collect();
+        let mut tokenizer =
+            Self::from_scored_pieces(vocab, vocab_scores, piece_types, max_token_length);
+        tokenizer.normalizers = vec![Normalizer::Prepend(" ".to_string())];
+        tokenizer.special_tokens = HashMap::from([
+            ("<unk>".to_string(), UNK_TOKEN),
+            ("<s>".to_string(), BOS_TOKEN),
+            ("</s>".to_string(), EOS_TOKEN),
+        ]);
+        tokenizer
+    }
+
+    // sentencepiece BPE, where the score of a normal piece is the priority of
+    // every merge that makes it
+    pub(crate) fn from_scored_pieces(
+        vocab: Vec<String>,
+        vocab_scores: Vec<f32>,
+        piece_types: Vec<PieceType>,
+        max_token_length: usize,
+    ) -> Self {
+        let mut tokenizer = Self::from_merges(
+            vocab,
+            vocab_scores,
+            piece_types,
+            max_token_length,
+            HashMap::new(),
+        );
+
+        // every way of splitting a piece into two pieces is a pair the BPE merges
+        let mut merges = HashMap::new();
+        tokenizer
+            .vocab
+            .iter()
+            .zip(tokenizer.piece_types.iter())
+            .enumerate()
+            .filter(|(_, (_, &piece_type))| piece_type == PieceType::Normal)
+            .for_each(|(id, (piece, _))| {
+                piece.char_indices().skip(1).for_each(|(i, _)| {
+                    let left = tokenizer.token_lookup(&piece[..i]);
+                    let right = tokenizer.token_lookup(&piece[i..]);
+                    if let (Some(left), Some(right)) = (left, right) {
+                        merges.insert((left, right), (id as u32, tokenizer.vocab_scores[id]));
+                    }
+                });
+            });
+        tokenizer.merges = merges;
+
+        tokenizer
+    }
+
+    // a vocab with explicit merges, without any normalizer or pre-tokenizer. the
+    // control and unknown pieces are the special tokens, and BOS, EOS and PAD are
+    // found among them by name, or are llama2.c's
+    pub(crate) fn from_merges(
+        vocab: Vec<String>,
+        vocab_scores: Vec<f32>,
+        piece_types: Vec<PieceType>,
+        max_token_length: usize,
+        merges: HashMap<(u32, u32), (u32, f32)>,
+    ) -> Self {
+        let byte_pieces: [u8; 256] = (0..=255).collect::<Vec<u8>>().try_into().unwrap();
+        let vocab_size = vocab.len() as u32;
+
         let mut vocab_sorted = (0..vocab_size).collect::<Vec<u32>>();
         vocab_sorted.sort_unstable_by(|a, b| vocab[*a as usize].cmp(&vocab[*b as usize]));
 
-        Ok(Self {
+        let mut tokenizer = Self {
             byte_pieces,
-            max_token_length: max_token_length as usize,
+            max_token_length,
             vocab: vocab.into_boxed_slice(),
             vocab_scores: vocab_scores.into_boxed_slice(),
             vocab_size,
             vocab_sorted: vocab_sorted.into_boxed_slice(),
-        })
+            piece_types: piece_types.into_boxed_slice(),
+            normalizers: vec![],
+            pre_tokenizers: vec![],
+            byte_fallback: true,
+            unk_token: UNK_TOKEN,
+            fuse_unk: false,
+            added_tokens: vec![],
+            added_tokens_normalized: false,
+            special_tokens: HashMap::new(),
+            parse_special: false,
+            bos_token: BOS_TOKEN,
+            eos_token: EOS_TOKEN,
+            pad_token: None,
+            end_tokens: vec![],
+            merges,
+            byte_tokens: [None; 256],
+        };
+        (0..vocab_size).for_each(|token| {
+            if let Some(b) = tokenizer.byte_fallback(token) {
+                tokenizer.byte_tokens[b as usize] = Some(token);
+            }
+            let piece_type = tokenizer.piece_types[token as usize];
+            if piece_type == PieceType::Control || piece_type == PieceType::Unknown {
+                let name = tokenizer.vocab[token as usize].clone();
+                tokenizer.special_tokens.insert(name, token);
+            }
+        });
+
+        let named = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|&name| tokenizer.special_tokens.get(name).copied())
+        };
+        tokenizer.bos_token = named(&BOS_NAMES).unwrap_or(BOS_TOKEN);
+        tokenizer.eos_token = named(&EOS_NAMES).unwrap_or(EOS_TOKEN);
+        tokenizer.pad_token = named(&PAD_NAMES);
+        tokenizer.reset_end_tokens();
+
+        tokenizer
+    }
+
+    // EOS and the special tokens that end a chat turn end the output, and so does
+    // BOS, as llama2.c's models start every story with it
+    pub fn reset_end_tokens(self: &mut Self) {
+        self.end_tokens = vec![self.bos_token, self.eos_token];
+        self.end_tokens.extend(
+            END_NAMES
+                .iter()
+                .filter_map(|&name| self.special_tokens.get(name).copied()),
+        );
+        self.end_tokens.sort_unstable();
+        self.end_tokens.dedup();
     }
 
-    pub fn token_lookup(self: &Self, token: &String) -> Option<u32> {
+    pub fn token_lookup(self: &Self, token: &str) -> Option<u32> {
         let res = self.vocab_sorted.binary_search_by(|&probe| {
-            let tok = &self.vocab[probe as usize];
+            let tok = self.vocab[probe as usize].as_str();
             tok.cmp(token)
         });
 
@@ -61,97 +366,1344 @@ impl Tokenizer {
     }
 
     pub fn encode(self: &Self, prompt: &str, bos: bool, eos: bool) -> Result<Vec<u32>, String> {
+        let (prompt_tokens, _) = self.encode_with_offsets(prompt, bos, eos)?;
+
+        debug!(
+            "prompt pieces: {:?}",
+            prompt_tokens
+                .iter()
+                .map(|&i| &self.vocab[i as usize])
+                .collect::<Vec<_>>()
+        );
+
+        Ok(prompt_tokens)
+    }
+
+    // the tokens and the (start, end) byte span of the prompt each one covers.
+    // BOS and EOS cover nothing at either end, the dummy prefix nothing before
+    // the first character, and tokens from text the normalizers changed the
+    // whole of what they were made from
+    pub fn encode_with_offsets(
+        self: &Self,
+        prompt: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<(Vec<u32>, Vec<Span>), String> {
         let mut prompt_tokens = vec![];
+        let mut offsets = vec![];
 
         if bos {
-            prompt_tokens.push(1);
+            prompt_tokens.push(self.bos_token);
+            offsets.push((0, 0));
+        }
+
+        if self.added_tokens_normalized {
+            let (text, spans) = self.normalize(prompt, true, true);
+            self.split_added_tokens(&text)
+                .into_iter()
+                .for_each(|(segment, (start, end))| match segment {
+                    Ok(id) => {
+                        prompt_tokens.push(id);
+                        offsets.push((spans[start].0, spans[end - 1].1));
+                    }
+                    Err(segment) => self
+                        .encode_normalized(segment, &spans[start..end])
+                        .into_iter()
+                        .for_each(|(token, span)| {
+                            prompt_tokens.push(token);
+                            offsets.push(span);
+                        }),
+                });
+        } else {
+            self.split_added_tokens(prompt)
+                .into_iter()
+                .enumerate()
+                .for_each(|(i, (segment, (start, end)))| match segment {
+                    Ok(id) => {
+                        prompt_tokens.push(id);
+                        offsets.push((start, end));
+                    }
+                    Err(text) => self
+                        .encode_text_with_offsets(text, true, i == 0)
+                        .into_iter()
+                        .for_each(|(token, (from, to))| {
+                            prompt_tokens.push(token);
+                            offsets.push((start + from, start + to));
+                        }),
+                });
         }
 
-        if prompt.len() > 0 {
-            let dummy_token = self.token_lookup(&" ".to_string());
-            prompt_tokens.push(dummy_token.unwrap());
+        if eos {
+            prompt_tokens.push(self.eos_token);
+            offsets.push((prompt.len(), prompt.len()));
         }
 
-        prompt_tokens.extend(prompt.graphemes(true).into_iter().flat_map(|x| {
-            let id = self.token_lookup(&x.to_string());
-            if id.is_some() {
-                vec![id.unwrap()]
-            } else {
-                x.as_bytes()
+        Ok((prompt_tokens, offsets))
+    }
+
+    // the text around added tokens, and special tokens with parse_special, as Err
+    // and the tokens as Ok, with their spans
+    fn split_added_tokens<'a>(self: &Self, text: &'a str) -> Vec<(Result<u32, &'a str>, Span)> {
+        let mut literals = self
+            .added_tokens
+            .iter()
+            .map(|&id| (self.vocab[id as usize].as_str(), id))
+            .collect::<Vec<_>>();
+        if self.parse_special {
+            literals.extend(
+                self.special_tokens
                     .iter()
-                    .map(|&b| (b + 3) as u32)
-                    .collect::<Vec<u32>>()
+                    .map(|(name, &id)| (name.as_str(), id)),
+            );
+        }
+        split_literals(text, &literals)
+    }
+
+    // the text after the normalizers, with the dummy prefix where prefix allows
+    // one and first tells whether the text starts the input. every normalised
+    // byte comes with the span of the text it was made from, which for a byte of
+    // an unchanged character is that byte, and for added characters is empty
+    fn normalize(self: &Self, text: &str, prefix: bool, first: bool) -> (String, Vec<Span>) {
+        let mut chars = text
+            .char_indices()
+            .map(|(i, c)| (c, (i, i + c.len_utf8())))
+            .collect::<Vec<Aligned>>();
+        self.normalizers.iter().for_each(|normalizer| {
+            chars = match normalizer {
+                Normalizer::Nfc => normalize_form(&chars, |s| s.nfc().collect()),
+                Normalizer::Nfd => normalize_form(&chars, |s| s.nfd().collect()),
+                Normalizer::Nfkc => normalize_form(&chars, |s| s.nfkc().collect()),
+                Normalizer::Nfkd => normalize_form(&chars, |s| s.nfkd().collect()),
+                Normalizer::Lowercase => chars
+                    .iter()
+                    .flat_map(|&(c, span)| c.to_lowercase().map(move |c| (c, span)))
+                    .collect(),
+                Normalizer::Prepend(p) => {
+                    if prefix && !chars.is_empty() {
+                        let (_, (at, _)) = chars[0];
+                        p.chars()
+                            .map(|c| (c, (at, at)))
+                            .chain(chars.drain(..))
+                            .collect()
+                    } else {
+                        std::mem::take(&mut chars)
+                    }
+                }
+                Normalizer::Replace(from, to) => replace(&chars, from, to),
+                Normalizer::Strip { left, right } => {
+                    let mut stripped = &chars[..];
+                    while *left && stripped.first().is_some_and(|c| c.0.is_whitespace()) {
+                        stripped = &stripped[1..];
+                    }
+                    while *right && stripped.last().is_some_and(|c| c.0.is_whitespace()) {
+                        stripped = &stripped[..stripped.len() - 1];
+                    }
+                    stripped.to_vec()
+                }
+                Normalizer::RemoveExtraWhitespaces => {
+                    let mut kept: Vec<Aligned> = vec![];
+                    chars.iter().for_each(|&(c, span)| {
+                        if c != ' ' || kept.last().is_some_and(|last| last.0 != ' ') {
+                            kept.push((c, span));
+                        }
+                    });
+                    if kept.last().is_some_and(|last| last.0 == ' ') {
+                        kept.pop();
+                    }
+                    kept
+                }
             }
-        }));
+        });
 
-        loop {
-            let mut best_score = f32::MIN;
-            let mut best_id = -1i32;
-            let mut best_idx = -1i32;
-
-            prompt_tokens.windows(2).enumerate().for_each(|(i, pair)| {
-                if let &[a, b] = pair {
-                    let merged_str =
-                        format!("{}{}", self.vocab[a as usize], self.vocab[b as usize]);
-                    let id = self.token_lookup(&merged_str.to_string());
-
-                    match id {
-                        Some(id) => {
-                            best_score = self.vocab_scores[id as usize];
-                            best_id = id as i32;
-                            best_idx = i as i32;
+        let metaspace_prefix = self.pre_tokenizers.iter().any(|p| match p {
+            PreTokenizer::Metaspace { prepend, .. } => match prepend {
+                PrependScheme::Always => prefix,
+                PrependScheme::First => prefix && first,
+                PrependScheme::Never => false,
+            },
+            _ => false,
+        });
+        if metaspace_prefix && chars.first().is_some_and(|c| c.0 != ' ') {
+            let (_, (at, _)) = chars[0];
+            chars.insert(0, (' ', (at, at)));
+        }
+
+        let mut normalized = String::new();
+        let mut spans = vec![];
+        chars.into_iter().for_each(|(c, (start, end))| {
+            normalized.push(c);
+            if text[start..end].chars().eq([c]) {
+                spans.extend((start..end).map(|i| (i, i + 1)));
+            } else {
+                spans.extend(std::iter::repeat_n((start, end), c.len_utf8()));
+            }
+        });
+        (normalized, spans)
+    }
+
+    // the words of normalised text, split by every pre-tokenizer in turn
+    fn pre_tokenize<'a>(self: &Self, text: &'a str) -> Vec<&'a str> {
+        let mut words = vec![text];
+        self.pre_tokenizers.iter().for_each(|pre_tokenizer| {
+            words = words
+                .iter()
+                .flat_map(|&word| -> Vec<&str> {
+                    match pre_tokenizer {
+                        PreTokenizer::Metaspace { split: false, .. } => vec![word],
+                        PreTokenizer::Metaspace { split: true, .. } => {
+                            let mut starts = word
+                                .match_indices(' ')
+                                .map(|(i, _)| i)
+                                .filter(|&i| i > 0)
+                                .collect::<Vec<usize>>();
+                            starts.insert(0, 0);
+                            starts.push(word.len());
+                            starts.windows(2).map(|w| &word[w[0]..w[1]]).collect()
+                        }
+                        PreTokenizer::Whitespace => WORDS
+                            .find_iter(word)
+                            .map(|m| &word[m.start()..m.end()])
+                            .collect(),
+                        PreTokenizer::WhitespaceSplit => word.split_whitespace().collect(),
+                        PreTokenizer::Digits { individual } => {
+                            let mut pieces = vec![];
+                            let mut start = 0;
+                            let mut prev_digit = None;
+                            word.char_indices().for_each(|(i, c)| {
+                                let digit = c.is_numeric();
+                                let boundary = match prev_digit {
+                                    None => false,
+                                    Some(prev) => prev != digit || (digit && *individual),
+                                };
+                                if boundary {
+                                    pieces.push(&word[start..i]);
+                                    start = i;
+                                }
+                                prev_digit = Some(digit);
+                            });
+                            pieces.push(&word[start..]);
+                            pieces
+                        }
+                    }
+                })
+                .filter(|word| !word.is_empty())
+                .collect();
+        });
+        words
+    }
+
+    // BPE encodes text without special tokens, optionally behind the dummy prefix
+    // space. follows encode in llama2.c: every code point starts as its own token
+    // or as byte-fallback tokens, then the highest scoring mergeable pair is merged
+    // until none is left, the leftmost one on ties. words from the pre-tokenizers
+    // are merged separately
+    fn encode_text(self: &Self, prompt: &str, dummy_prefix: bool, first: bool) -> Vec<u32> {
+        self.encode_text_with_offsets(prompt, dummy_prefix, first)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    // encode_text with the span of the prompt every token covers
+    fn encode_text_with_offsets(
+        self: &Self,
+        prompt: &str,
+        dummy_prefix: bool,
+        first: bool,
+    ) -> Vec<(u32, Span)> {
+        let (text, spans) = self.normalize(prompt, dummy_prefix, first);
+        self.encode_normalized(&text, &spans)
+    }
+
+    // normalised text as tokens, with the span of the prompt each covers, where
+    // spans holds the span of every byte of the text
+    fn encode_normalized(self: &Self, text: &str, spans: &[Span]) -> Vec<(u32, Span)> {
+        let mut prompt_tokens = vec![];
+        let mut buf = [0u8; 4];
+        self.pre_tokenize(text).into_iter().for_each(|word| {
+            let offset = word.as_ptr() as usize - text.as_ptr() as usize;
+            // the tokens before merging, and where in the text each one starts. a
+            // fused unknown token runs on to the start of the next one
+            let mut word_tokens = vec![];
+            let mut starts = vec![];
+            word.char_indices().for_each(|(i, c)| {
+                let start = offset + i;
+                let c = c.encode_utf8(&mut buf);
+                match self.token_lookup(c) {
+                    Some(id) => {
+                        word_tokens.push(id);
+                        starts.push(start);
+                    }
+                    None => {
+                        let bytes = c.bytes().map(|b| self.byte_tokens[b as usize]);
+                        match bytes.collect::<Option<Vec<u32>>>() {
+                            Some(bytes) if self.byte_fallback => {
+                                starts.extend(start..start + bytes.len());
+                                word_tokens.extend(bytes);
+                            }
+                            _ if self.fuse_unk && word_tokens.last() == Some(&self.unk_token) => {}
+                            _ => {
+                                word_tokens.push(self.unk_token);
+                                starts.push(start);
+                            }
                         }
-                        None => {}
                     }
                 }
             });
 
-            if best_idx == -1 {
-                break;
+            let merged = self.merge(word_tokens);
+            let ends = merged
+                .iter()
+                .skip(1)
+                .map(|&(position, _)| starts[position])
+                .chain([offset + word.len()]);
+            merged
+                .iter()
+                .zip(ends)
+                .for_each(|(&(position, token), end)| {
+                    let (start, _) = spans[starts[position]];
+                    let (_, end) = spans[end - 1];
+                    prompt_tokens.push((token, (start, end.max(start))));
+                });
+        });
+
+        prompt_tokens
+    }
+
+    // the tokens live in a linked list over their starting positions, and every
+    // adjacent pair that merges sits in a heap ordered by score, then position.
+    // a merge replaces the left token and unlinks the right one, so positions keep
+    // their order and heap entries whose tokens have changed since are skipped.
+    // returns the merged tokens with the position of the first token each is made of
+    fn merge(self: &Self, mut tokens: Vec<u32>) -> Vec<(usize, u32)> {
+        const NONE: usize = usize::MAX;
+        let n = tokens.len();
+        let mut next = (1..=n)
+            .map(|i| if i < n { i } else { NONE })
+            .collect::<Vec<usize>>();
+        let mut prev = (0..n).map(|i| i.wrapping_sub(1)).collect::<Vec<usize>>();
+        if n > 0 {
+            prev[0] = NONE;
+        }
+        let mut removed = vec![false; n];
+
+        let mut heap = BinaryHeap::new();
+        let candidate = |tokens: &[u32], left: usize, right: usize| {
+            let (id, score) = *self.merges.get(&(tokens[left], tokens[right]))?;
+            (score > -1e10f32).then_some(Merge {
+                score,
+                left,
+                right,
+                pair: (tokens[left], tokens[right]),
+                id,
+            })
+        };
+        (1..n).for_each(|i| heap.extend(candidate(&tokens, i - 1, i)));
+
+        while let Some(merge) = heap.pop() {
+            if removed[merge.left]
+                || removed[merge.right]
+                || next[merge.left] != merge.right
+                || (tokens[merge.left], tokens[merge.right]) != merge.pair
+            {
+                continue;
             }
 
-            prompt_tokens[best_idx as usize] = best_id as u32;
-            prompt_tokens.remove((best_idx + 1) as usize);
+            tokens[merge.left] = merge.id;
+            removed[merge.right] = true;
+            next[merge.left] = next[merge.right];
+            if next[merge.left] != NONE {
+                prev[next[merge.left]] = merge.left;
+                heap.extend(candidate(&tokens, merge.left, next[merge.left]));
+            }
+            if prev[merge.left] != NONE {
+                heap.extend(candidate(&tokens, prev[merge.left], merge.left));
+            }
         }
 
-        println!(
-            "{:?}",
-            prompt_tokens
+        tokens
+            .into_iter()
+            .zip(removed)
+            .enumerate()
+            .filter(|(_, (_, removed))| !removed)
+            .map(|(position, (token, _))| (position, token))
+            .collect()
+    }
+
+    // the raw byte behind a <0xXX> byte-fallback token
+    pub fn byte_fallback(self: &Self, token: u32) -> Option<u8> {
+        if *self.piece_types.get(token as usize)? != PieceType::Byte {
+            return None;
+        }
+        utils::parse_hex_byte(&self.vocab[token as usize]).ok()
+    }
+
+    // the bytes of a token's piece, with <0xXX> tokens turned back into their byte
+    pub fn decode_bytes(self: &Self, token: u32, prev_token: u32) -> Result<&[u8], String> {
+        let piece = self
+            .vocab
+            .get(token as usize)
+            .ok_or_else(|| format!("token {} is outside the vocab", token))?;
+
+        if let Some(b) = self.byte_fallback(token) {
+            return Ok(std::slice::from_ref(&self.byte_pieces[b as usize]));
+        }
+
+        // following BOS, sentencepiece strips the leading whitespace
+        if prev_token == self.bos_token {
+            Ok(piece.strip_prefix(' ').unwrap_or(piece).as_bytes())
+        } else {
+            Ok(piece.as_bytes())
+        }
+    }
+
+    // a single token, where a byte token that is not a whole character on its own
+    // decodes to U+FFFD. use StreamDecoder for running text
+    pub fn decode(self: &Self, token: u32, prev_token: u32) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.decode_bytes(token, prev_token)?).into_owned())
+    }
+
+    // the text a whole sequence of tokens was encoded from. control tokens like BOS
+    // and EOS are left out, and the dummy prefix comes off the text wherever encode
+    // put it: at the start and after every added token. decode_all(encode(s)) is s
+    // for any s, unless the normalizers do more than prepend the prefix, or code
+    // points outside the vocab became <unk> without byte fallback
+    pub fn decode_all(self: &Self, tokens: &[u32]) -> Result<String, String> {
+        let mut text = vec![];
+        // the text since the last added or control token
+        let mut segment = vec![];
+        let mut first = true;
+        for &token in tokens {
+            let piece_type = *self
+                .piece_types
+                .get(token as usize)
+                .ok_or_else(|| format!("token {} is outside the vocab", token))?;
+            let added = self.added_tokens.contains(&token);
+            if piece_type == PieceType::Control || added {
+                text.extend_from_slice(self.strip_dummy_prefix(&segment, first));
+                first &= segment.is_empty() && !added;
+                segment.clear();
+                if added {
+                    text.extend_from_slice(self.vocab[token as usize].as_bytes());
+                }
+            } else {
+                match self.byte_fallback(token) {
+                    Some(b) => segment.push(self.byte_pieces[b as usize]),
+                    None => segment.extend_from_slice(self.vocab[token as usize].as_bytes()),
+                }
+            }
+        }
+        text.extend_from_slice(self.strip_dummy_prefix(&segment, first));
+
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    // the text encode_text made tokens of, before the prefixes of normalize
+    fn strip_dummy_prefix<'a>(self: &Self, segment: &'a [u8], first: bool) -> &'a [u8] {
+        let mut segment = segment;
+        // the last Prepend ends up in front
+        self.normalizers.iter().rev().for_each(|normalizer| {
+            if let Normalizer::Prepend(p) = normalizer {
+                if first || !self.added_tokens_normalized {
+                    segment = segment.strip_prefix(p.as_bytes()).unwrap_or(segment);
+                }
+            }
+        });
+        // a Metaspace prefix only goes before text that does not start with a
+        // space already, so a space the text started with is lost here
+        let metaspace_prefix = self.pre_tokenizers.iter().any(|p| match p {
+            PreTokenizer::Metaspace { prepend, .. } => match prepend {
+                PrependScheme::Always => true,
+                PrependScheme::First => first,
+                PrependScheme::Never => false,
+            },
+            _ => false,
+        });
+        if metaspace_prefix {
+            segment = segment.strip_prefix(b" ").unwrap_or(segment);
+        }
+        segment
+    }
+}
+
+// the text around the literals as Err, and the literals as Ok with their ids,
+// leftmost and then longest match first, each with its span of the text
+pub(crate) fn split_literals<'a>(
+    text: &'a str,
+    literals: &[(&str, u32)],
+) -> Vec<(Result<u32, &'a str>, Span)> {
+    let mut segments = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let literal = literals
+            .iter()
+            .filter(|(literal, _)| !literal.is_empty() && text[i..].starts_with(literal))
+            .max_by_key(|(literal, _)| literal.len());
+        match literal {
+            Some(&(literal, id)) => {
+                if start < i {
+                    segments.push((Err(&text[start..i]), (start, i)));
+                }
+                segments.push((Ok(id), (i, i + literal.len())));
+                i += literal.len();
+                start = i;
+            }
+            None => i += text[i..].chars().next().unwrap().len_utf8(),
+        }
+    }
+    if start < text.len() {
+        segments.push((Err(&text[start..]), (start, text.len())));
+    }
+    segments
+}
+
+// a normalised character and the span of the text it was made from
+type Aligned = (char, Span);
+
+// a unicode normal form, applied to every character along with the combining
+// marks and characters that compose with it, as composition never reaches any
+// further. what a changed run becomes shares the span of the whole run
+fn normalize_form(chars: &[Aligned], form: impl Fn(&str) -> String) -> Vec<Aligned> {
+    let mut normalized = vec![];
+    let mut start = 0;
+    (1..=chars.len()).for_each(|i| {
+        let boundary = i == chars.len()
+            || (canonical_combining_class(chars[i].0) == 0
+                && compose(chars[i - 1].0, chars[i].0).is_none());
+        if boundary {
+            let run = &chars[start..i];
+            let text = run.iter().map(|&(c, _)| c).collect::<String>();
+            let formed = form(&text);
+            if formed == text {
+                normalized.extend_from_slice(run);
+            } else {
+                let ((_, (start, _)), (_, (_, end))) = (run[0], run[run.len() - 1]);
+                let span = (start, end);
+                normalized.extend(formed.chars().map(|c| (c, span)));
+            }
+            start = i;
+        }
+    });
+    normalized
+}
+
+// every match of from, leftmost first, replaced by to, which takes the span of
+// the match
+fn replace(chars: &[Aligned], from: &str, to: &str) -> Vec<Aligned> {
+    let text = chars.iter().map(|&(c, _)| c).collect::<String>();
+    // the character at every byte offset of text, and one past the end
+    let mut char_at = vec![0; text.len() + 1];
+    text.char_indices()
+        .enumerate()
+        .for_each(|(n, (i, c))| char_at[i..i + c.len_utf8()].fill(n));
+    char_at[text.len()] = chars.len();
+
+    let mut replaced = vec![];
+    let mut last = 0;
+    text.match_indices(from).for_each(|(i, m)| {
+        let (first, after) = (char_at[i], char_at[i + m.len()]);
+        replaced.extend_from_slice(&chars[last..first]);
+        let span = if first < after {
+            let ((_, (start, _)), (_, (_, end))) = (chars[first], chars[after - 1]);
+            (start, end)
+        } else {
+            let at = chars
+                .get(first)
+                .map(|&(_, (start, _))| start)
+                .or(chars.last().map(|&(_, (_, end))| end))
+                .unwrap_or(0);
+            (at, at)
+        };
+        replaced.extend(to.chars().map(|c| (c, span)));
+        last = after;
+    });
+    replaced.extend_from_slice(&chars[last..]);
+    replaced
+}
+
+pub(crate) fn is_byte_piece(piece: &str) -> bool {
+    piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>')
+}
+
+impl Tokenize for Tokenizer {
+    fn vocab_size(self: &Self) -> u32 {
+        self.vocab_size
+    }
+
+    fn encode(self: &Self, text: &str, bos: bool, eos: bool) -> Result<Vec<u32>, String> {
+        Tokenizer::encode(self, text, bos, eos)
+    }
+
+    fn encode_fragment(self: &Self, text: &str) -> Vec<u32> {
+        self.encode_text(text, false, false)
+    }
+
+    fn decode_bytes(self: &Self, token: u32, prev_token: u32) -> Result<&[u8], String> {
+        Tokenizer::decode_bytes(self, token, prev_token)
+    }
+
+    fn decode_all(self: &Self, tokens: &[u32]) -> Result<String, String> {
+        Tokenizer::decode_all(self, tokens)
+    }
+
+    fn piece(self: &Self, token: u32) -> String {
+        self.vocab[token as usize].clone()
+    }
+
+    fn is_control(self: &Self, token: u32) -> bool {
+        matches!(
+            self.piece_types[token as usize],
+            PieceType::Control | PieceType::Unknown
+        )
+    }
+
+    fn bos_token(self: &Self) -> u32 {
+        self.bos_token
+    }
+
+    fn eos_token(self: &Self) -> u32 {
+        self.eos_token
+    }
+
+    fn pad_token(self: &Self) -> Option<u32> {
+        self.pad_token
+    }
+
+    fn end_tokens(self: &Self) -> &[u32] {
+        &self.end_tokens
+    }
+}
+
+// What generate and StreamDecoder need from a tokenizer, implemented by the
+// sentencepiece-style Tokenizer and the byte-level Tiktoken of llama 3
+pub trait Tokenize {
+    fn vocab_size(self: &Self) -> u32;
+    fn encode(self: &Self, text: &str, bos: bool, eos: bool) -> Result<Vec<u32>, String>;
+    // text as it would appear mid-text, without BOS or a dummy prefix
+    fn encode_fragment(self: &Self, text: &str) -> Vec<u32>;
+    // the bytes the token adds to the output when it follows prev_token
+    fn decode_bytes(self: &Self, token: u32, prev_token: u32) -> Result<&[u8], String>;
+    // the text an encoded sequence came from, without its special tokens
+    fn decode_all(self: &Self, tokens: &[u32]) -> Result<String, String>;
+    // the token as the vocab writes it
+    fn piece(self: &Self, token: u32) -> String;
+    // <unk> and the other tokens that stand for no text, which constraints never allow
+    fn is_control(self: &Self, token: u32) -> bool;
+    fn bos_token(self: &Self) -> u32;
+    fn eos_token(self: &Self) -> u32;
+    fn pad_token(self: &Self) -> Option<u32>;
+    // the tokens the model ends its output with
+    fn end_tokens(self: &Self) -> &[u32];
+
+    fn is_end_token(self: &Self, token: u32) -> bool {
+        self.end_tokens().contains(&token)
+    }
+
+    // logit bias for the token each string encodes to, for Sampler::logit_bias.
+    // a string spanning several tokens is an error, biasing its pieces would
+    // shift every other word they occur in
+    fn logit_bias_from_strs(
+        self: &Self,
+        biases: &[(&str, f32)],
+    ) -> Result<HashMap<u32, f32>, String> {
+        let mut logit_bias = HashMap::new();
+        for &(s, bias) in biases {
+            match self.encode_fragment(s)[..] {
+                [token] => *logit_bias.entry(token).or_insert(0f32) += bias,
+                ref tokens => {
+                    return Err(format!(
+                        "{:?} is {} tokens, not a single one",
+                        s,
+                        tokens.len()
+                    ))
+                }
+            }
+        }
+        Ok(logit_bias)
+    }
+
+    // bans the strings from the output however they are tokenized, for
+    // Sampler::banned_strings
+    fn ban_list_from_strs(self: &Self, strs: &[&str]) -> BannedStrings {
+        // what each token adds mid-text, where no token follows BOS
+        let token_bytes = (0..self.vocab_size())
+            .map(|token| self.decode_bytes(token, u32::MAX).unwrap_or(&[]).into())
+            .collect();
+        BannedStrings::new(token_bytes, strs)
+    }
+}
+
+// Decodes generated tokens one at a time. Characters split over byte tokens are
+// buffered until their last byte arrives, so only whole characters are returned,
+// and bytes that cannot be part of valid UTF-8 come out as U+FFFD.
+pub struct StreamDecoder<'a> {
+    tokenizer: &'a dyn Tokenize,
+    prev_token: u32,
+    pending: Vec<u8>,
+}
+
+impl<'a> StreamDecoder<'a> {
+    // prev_token is the last token before the ones to decode, e.g. the end of the prompt
+    pub fn new(tokenizer: &'a dyn Tokenize, prev_token: u32) -> Self {
+        StreamDecoder {
+            tokenizer,
+            prev_token,
+            pending: vec![],
+        }
+    }
+
+    // the text completed by the token, possibly empty
+    pub fn push(self: &mut Self, token: u32) -> Result<String, String> {
+        let bytes = self.tokenizer.decode_bytes(token, self.prev_token)?;
+        self.prev_token = token;
+        self.pending.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        loop {
+            match str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    text.push_str(s);
+                    self.pending.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    text.push_str(str::from_utf8(&self.pending[..valid]).unwrap());
+                    match e.error_len() {
+                        // an incomplete character at the end, wait for the rest
+                        None => {
+                            self.pending.drain(..valid);
+                            break;
+                        }
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(text)
+    }
+
+    // whatever is left of an incomplete character, once no more tokens follow
+    pub fn finish(self: &mut Self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Tokenizer {
+    // llama2-style vocab: <unk>, BOS, EOS, the 256 byte tokens, printable ascii,
+    // then the given merged pieces and their scores
+    pub fn with_pieces(pieces: &[(&str, f32)]) -> Self {
+        let mut vocab = vec![
+            "<unk>".to_string(),
+            "\n<s>\n".to_string(),
+            "\n</s>\n".to_string(),
+        ];
+        vocab.extend((0..=255).map(|b| format!("<0x{:02X}>", b)));
+        vocab.extend((b' '..=b'~').map(|c| (c as char).to_string()));
+        let mut vocab_scores = vec![0f32; vocab.len()];
+
+        pieces.iter().for_each(|&(piece, score)| {
+            vocab.push(piece.to_string());
+            vocab_scores.push(score);
+        });
+
+        let max_token_length = vocab.iter().map(|v| v.len()).max().unwrap();
+        Self::from_vocab(vocab, vocab_scores, max_token_length)
+    }
+}
+
+#[cfg(test)]
+impl Tokenizer {
+    // checks encode against the ids sentencepiece gives the llama 2 tokenizer for
+    // the strings of assets/tokenizer_corpus.json, exported by export_golden in
+    // assets/tokenizer.py:
+    // python assets/tokenizer.py -t assets/tokenizer.model \
+    //     --golden assets/tokenizer_corpus.json assets/tokenizer_golden.json
+    pub fn assert_golden_token_ids(self: &Self) {
+        let golden = std::fs::read_to_string("assets/tokenizer_golden.json")
+            .expect("assets/tokenizer_golden.json is missing, export it with assets/tokenizer.py");
+        let golden: serde_json::Value = serde_json::from_str(&golden).unwrap();
+
+        golden.as_array().unwrap().iter().for_each(|case| {
+            let text = case["text"].as_str().unwrap();
+            let ids = case["ids"]
+                .as_array()
+                .unwrap()
                 .iter()
-                .map(|&i| { self.vocab.get(i as usize).unwrap() })
-                .collect::<Vec<_>>()
+                .map(|id| id.as_u64().unwrap() as u32)
+                .collect::<Vec<u32>>();
+            assert_eq!(self.encode(text, true, false).unwrap(), ids, "{:?}", text);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Sampler;
+    use proptest::prelude::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn shoggoth_tokenizer() -> Tokenizer {
+        Tokenizer::with_pieces(&[
+            (" S", 1f32),
+            (" Sh", 2f32),
+            ("og", 3f32),
+            ("ogg", 4f32),
+            ("ot", 5f32),
+            ("oth", 6f32),
+        ])
+    }
+
+    fn token(tokenizer: &Tokenizer, piece: &str) -> u32 {
+        tokenizer.token_lookup(piece).unwrap()
+    }
+
+    #[test]
+    fn test_ban_list_from_strs() {
+        let tokenizer = shoggoth_tokenizer();
+        let banned = tokenizer.ban_list_from_strs(&[" Shoggoth"]);
+        let is_masked = |history: &[&str], next: &str| {
+            let history = history
+                .iter()
+                .map(|p| token(&tokenizer, p))
+                .collect::<Vec<u32>>();
+            let mut logits = vec![0f32; tokenizer.vocab_size as usize];
+            banned.mask_logits(&mut logits, &history);
+            logits[token(&tokenizer, next) as usize] == f32::NEG_INFINITY
+        };
+
+        // whichever way the string is spelled, the token that would finish it is masked
+        assert!(is_masked(&[" Sh", "ogg"], "oth"));
+        assert!(is_masked(&[" S", "h", "o", "g", "g", "o", "t"], "h"));
+        assert!(is_masked(&[" Sh", "og", "g", "ot"], "h"));
+        assert!(is_masked(&["x", " S", "h", "og", "g"], "oth"));
+        // and its pieces stay free everywhere else
+        assert!(!is_masked(&[], " Sh"));
+        assert!(!is_masked(&[" Sh", "ogg"], "ot"));
+        assert!(!is_masked(&[" Sh", "og"], "oth"));
+        assert!(!is_masked(&["x", "ogg"], "oth"));
+
+        // random walks over the pieces of the string never spell it out
+        let alphabet = [
+            " S", " Sh", "S", "h", "o", "g", "og", "ogg", "ot", "oth", "t", " ",
+        ]
+        .map(|p| token(&tokenizer, p));
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut sampler = Sampler {
+            banned_strings: banned,
+            ..Sampler::new(tokenizer.vocab_size as i32, 1f32, 0f32, 6)
+        };
+        let mut spelled = 0;
+        for _ in 0..200 {
+            let mut history = vec![];
+            for _ in 0..40 {
+                let mut logits = vec![f32::NEG_INFINITY; tokenizer.vocab_size as usize];
+                alphabet
+                    .iter()
+                    .for_each(|&t| logits[t as usize] = rng.gen());
+                history.push(sampler.sample(&mut logits, &history) as u32);
+            }
+            let text = tokenizer.decode_all(&history).unwrap();
+            assert!(!text.contains(" Shoggoth"), "{:?}", text);
+            spelled += text.contains(" Shoggot") as usize;
+        }
+        // the walks did get as far as the last character
+        assert!(spelled > 0);
+    }
+
+    #[test]
+    fn test_logit_bias_from_strs() {
+        let tokenizer = shoggoth_tokenizer();
+        let bias = tokenizer
+            .logit_bias_from_strs(&[("og", -2f32), ("og", -1f32), ("x", 5f32)])
+            .unwrap();
+        assert_eq!(bias.len(), 2);
+        assert_eq!(bias[&token(&tokenizer, "og")], -3f32);
+        assert_eq!(bias[&token(&tokenizer, "x")], 5f32);
+
+        let err = tokenizer
+            .logit_bias_from_strs(&[(" Shoggoth", -5f32)])
+            .unwrap_err();
+        assert!(err.contains("3 tokens"), "{}", err);
+    }
+
+    fn encode_pieces(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        tokenizer
+            .encode_text(text, false, false)
+            .iter()
+            .map(|&t| tokenizer.vocab[t as usize].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_picks_highest_score() {
+        // both pairs of "abc" merge, the better one has to win wherever it is
+        let tokenizer = Tokenizer::with_pieces(&[("ab", 5f32), ("bc", 1f32)]);
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec!["ab", "c"]);
+
+        let tokenizer = Tokenizer::with_pieces(&[("ab", 1f32), ("bc", 5f32)]);
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec!["a", "bc"]);
+
+        // ties go to the leftmost pair
+        let tokenizer = Tokenizer::with_pieces(&[("ab", 2f32), ("bc", 2f32)]);
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec!["ab", "c"]);
+    }
+
+    #[test]
+    fn test_merge_order() {
+        // "cd" merges first and takes the c away from "bc", so "abcd" is built
+        // from "ab" + "cd" although "bc" scores higher than "ab"
+        let tokenizer = Tokenizer::with_pieces(&[
+            ("ab", 1f32),
+            ("bc", 2f32),
+            ("cd", 3f32),
+            ("abcd", 0f32),
+            ("abc", -1f32),
+        ]);
+        assert_eq!(encode_pieces(&tokenizer, "abcd"), vec!["abcd"]);
+        assert_eq!(encode_pieces(&tokenizer, "abcdbc"), vec!["abcd", "bc"]);
+        assert_eq!(encode_pieces(&tokenizer, "xabc"), vec!["x", "abc"]);
+    }
+
+    #[test]
+    fn test_dummy_prefix() {
+        let tokenizer = Tokenizer::with_pieces(&[(" S", 1f32), (" Sh", 2f32)]);
+        let tokens = tokenizer.encode("Sh", true, false).unwrap();
+        assert_eq!(tokens[0], 1);
+        assert_eq!(tokenizer.vocab[tokens[1] as usize], " Sh");
+        assert_eq!(tokens.len(), 2);
+
+        assert_eq!(tokenizer.encode("", true, true).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let mut tokenizer = Tokenizer::with_pieces(&[("<s", 1f32)]);
+        assert_eq!(tokenizer.special_tokens["<s>"], BOS_TOKEN);
+        assert_eq!(tokenizer.special_tokens["</s>"], EOS_TOKEN);
+        assert_eq!(tokenizer.end_tokens, vec![BOS_TOKEN, EOS_TOKEN]);
+
+        // literal special tokens are text unless parsed
+        let text = tokenizer.encode("a</s><s>", false, false).unwrap();
+        assert!(!text.contains(&EOS_TOKEN) && !text.contains(&BOS_TOKEN));
+        tokenizer.parse_special = true;
+        let tokens = tokenizer.encode("a</s><s>", false, false).unwrap();
+        assert_eq!(tokens[2..], [EOS_TOKEN, BOS_TOKEN]);
+        assert_eq!(tokenizer.vocab[tokens[1] as usize], "a");
+
+        // configured ids are used for encoding, decoding and ending the output
+        let a = tokenizer.token_lookup("a").unwrap();
+        tokenizer.bos_token = a;
+        tokenizer.eos_token = a + 1;
+        tokenizer.reset_end_tokens();
+        assert_eq!(tokenizer.end_tokens, vec![a, a + 1]);
+        assert!(tokenizer.is_end_token(a + 1) && !tokenizer.is_end_token(EOS_TOKEN));
+        assert_eq!(tokenizer.encode("", true, true).unwrap(), vec![a, a + 1]);
+        let space = tokenizer.token_lookup(" ").unwrap();
+        assert_eq!(tokenizer.decode(space, a).unwrap(), "");
+        assert_eq!(tokenizer.decode(space, BOS_TOKEN).unwrap(), " ");
+    }
+
+    #[test]
+    fn test_code_points_fall_back_to_bytes() {
+        let tokenizer = Tokenizer::with_pieces(&[("é", 1f32)]);
+        let e = tokenizer.token_lookup("é").unwrap();
+        assert_eq!(tokenizer.encode_text("é", false, false), vec![e]);
+
+        // code points, not graphemes: the e is found, the combining accent is
+        // two byte tokens
+        assert_eq!(
+            encode_pieces(&tokenizer, "e\u{301}"),
+            vec!["e", "<0xCC>", "<0x81>"]
+        );
+        assert_eq!(
+            encode_pieces(&tokenizer, "🐻"),
+            vec!["<0xF0>", "<0x9F>", "<0x90>", "<0xBB>"]
         );
+    }
 
-        if eos {
-            prompt_tokens.push(2);
+    // neither the model nor the ids it gives are part of the repo
+    #[test]
+    #[ignore = "needs assets/tokenizer.bin and assets/tokenizer_golden.json from assets/tokenizer.py"]
+    fn test_golden_token_ids() {
+        Tokenizer::new("assets/tokenizer.bin", 32000)
+            .unwrap()
+            .assert_golden_token_ids();
+    }
+
+    fn stream(tokenizer: &Tokenizer, pieces: &[&str]) -> Vec<String> {
+        let mut decoder = StreamDecoder::new(tokenizer, 0);
+        let mut texts = pieces
+            .iter()
+            .map(|p| {
+                let token = tokenizer.token_lookup(p).unwrap();
+                decoder.push(token).unwrap()
+            })
+            .collect::<Vec<String>>();
+        texts.push(decoder.finish());
+        texts
+    }
+
+    #[test]
+    fn test_decode_byte_tokens() {
+        let tokenizer = Tokenizer::with_pieces(&[(" Sh", 1f32)]);
+        let lookup = |p: &str| tokenizer.token_lookup(p).unwrap();
+        assert_eq!(tokenizer.decode(lookup("<0x41>"), 0).unwrap(), "A");
+        assert_eq!(tokenizer.decode(lookup("<0x0A>"), 0).unwrap(), "\n");
+        assert_eq!(tokenizer.decode(lookup("<0xE2>"), 0).unwrap(), "\u{FFFD}");
+        assert_eq!(
+            tokenizer.decode_bytes(lookup("<0xE2>"), 0).unwrap(),
+            &[0xE2]
+        );
+
+        // the leading space goes after BOS only
+        assert_eq!(tokenizer.decode(lookup(" Sh"), 1).unwrap(), "Sh");
+        assert_eq!(tokenizer.decode(lookup(" Sh"), 5).unwrap(), " Sh");
+        assert!(tokenizer.decode(tokenizer.vocab_size, 0).is_err());
+    }
+
+    #[test]
+    fn test_stream_decoder_assembles_characters() {
+        let tokenizer = Tokenizer::with_pieces(&[(" ours", 1f32)]);
+        // 🐻 is F0 9F 90 BB
+        assert_eq!(
+            stream(
+                &tokenizer,
+                &[" ours", " ", "<0xF0>", "<0x9F>", "<0x90>", "<0xBB>", "!"]
+            ),
+            vec![" ours", " ", "", "", "", "🐻", "!", ""]
+        );
+
+        // é is C3 A9, Я is D0 AF
+        assert_eq!(
+            stream(&tokenizer, &["<0xC3>", "<0xA9>", "<0xD0>", "<0xAF>"]).concat(),
+            "éЯ"
+        );
+    }
+
+    #[test]
+    fn test_stream_decoder_invalid_bytes() {
+        let tokenizer = Tokenizer::with_pieces(&[]);
+        // a continuation byte on its own, and a lead byte cut off by ascii
+        assert_eq!(
+            stream(&tokenizer, &["<0xA9>", "a", "<0xE2>", "<0x82>", "b"]),
+            vec!["\u{FFFD}", "a", "", "", "\u{FFFD}b", ""]
+        );
+
+        // a character still incomplete at the end
+        assert_eq!(
+            stream(&tokenizer, &["x", "<0xF0>", "<0x9F>"]),
+            vec!["x", "", "", "\u{FFFD}"]
+        );
+    }
+
+    // the quadratic merge loop of llama2.c, rescanning every pair after each merge
+    fn merge_reference(tokenizer: &Tokenizer, mut tokens: Vec<u32>) -> Vec<u32> {
+        loop {
+            let mut best_score = -1e10f32;
+            let mut best_id = None;
+            let mut best_idx = 0;
+
+            tokens.windows(2).enumerate().for_each(|(i, pair)| {
+                let merged_str = format!(
+                    "{}{}",
+                    tokenizer.vocab[pair[0] as usize], tokenizer.vocab[pair[1] as usize]
+                );
+                if let Some(id) = tokenizer.token_lookup(&merged_str) {
+                    if tokenizer.vocab_scores[id as usize] > best_score {
+                        best_score = tokenizer.vocab_scores[id as usize];
+                        best_id = Some(id);
+                        best_idx = i;
+                    }
+                }
+            });
+
+            let Some(best_id) = best_id else {
+                return tokens;
+            };
+
+            tokens[best_idx] = best_id;
+            tokens.remove(best_idx + 1);
         }
+    }
 
-        Ok(prompt_tokens)
+    // random merges over a small alphabet, with plenty of equal scores
+    fn random_tokenizer(rng: &mut StdRng) -> Tokenizer {
+        let alphabet = ['a', 'b', 'c', ' ', 'é'];
+        let mut pieces = vec![];
+        for len in 2..=6 {
+            for _ in 0..40 {
+                let piece = (0..len)
+                    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                    .collect::<String>();
+                pieces.push((piece, rng.gen_range(-3..3) as f32));
+            }
+        }
+        pieces.sort_by(|a, b| a.0.cmp(&b.0));
+        pieces.dedup_by(|a, b| a.0 == b.0);
+        let pieces = pieces
+            .iter()
+            .map(|(p, score)| (p.as_str(), *score))
+            .collect::<Vec<_>>();
+        Tokenizer::with_pieces(&pieces)
     }
 
-    pub fn decode(self: &Self, token: u32, prev_token: u32) -> Result<String, String> {
-        let mut piece = self.vocab.get(token as usize).unwrap().clone();
-
-        if prev_token == 1 && piece.chars().nth(0).unwrap() == ' ' {
-            piece = piece.strip_prefix(' ').unwrap().to_string();
-        }
-
-        // match utils::parse_hex_byte(piece.as_str()) {
-        //     Ok(b) => {
-        //         piece = match str::from_utf8(&[b]) {
-        //             Ok(b_str) => {
-        //                 println!("{:?} {:?}", b_str, b);
-        //                 b_str.to_string()
-        //             }
-        //             Err(_) => {
-        //                 println!("xx {:?}", b);
-        //                 piece
-        //             }
-        //         };
-        //     }
-        //     Err(_) => {}
-        // }
-
-        Ok(piece)
+    fn random_text(rng: &mut StdRng, len: usize) -> String {
+        let alphabet = ['a', 'b', 'c', ' ', 'é', 'd'];
+        (0..len)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+            .collect()
+    }
+
+    // the tokens encode_text starts merging from
+    fn unmerged(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        if text.is_empty() {
+            return vec![];
+        }
+        let mut tokens = vec![tokenizer.token_lookup(" ").unwrap()];
+        text.chars().for_each(|c| {
+            let c = c.to_string();
+            match tokenizer.token_lookup(&c) {
+                Some(id) => tokens.push(id),
+                None => tokens.extend(c.bytes().map(|b| b as u32 + 3)),
+            }
+        });
+        tokens
+    }
+
+    #[test]
+    fn test_merge_matches_reference() {
+        let mut rng = StdRng::seed_from_u64(19);
+        for _ in 0..50 {
+            let tokenizer = random_tokenizer(&mut rng);
+            for len in [0, 1, 2, 5, 30, 200] {
+                let text = random_text(&mut rng, len);
+                assert_eq!(
+                    tokenizer.encode_text(&text, true, true),
+                    merge_reference(&tokenizer, unmerged(&tokenizer, &text)),
+                    "{:?}",
+                    text
+                );
+            }
+        }
+    }
+
+    // the spans follow each other over the whole prompt, and every token decodes
+    // to the text of its span
+    fn assert_offsets_round_trip(tokenizer: &Tokenizer, prompt: &str) {
+        let (tokens, offsets) = tokenizer.encode_with_offsets(prompt, true, false).unwrap();
+        assert_eq!(tokens, tokenizer.encode(prompt, true, false).unwrap());
+        assert_eq!(offsets[0], (0, 0));
+
+        let mut covered = 0;
+        tokens
+            .windows(2)
+            .zip(&offsets[1..])
+            .for_each(|(pair, &(start, end))| {
+                assert_eq!(start, covered, "{:?}", prompt);
+                assert_eq!(
+                    tokenizer.decode_bytes(pair[1], pair[0]).unwrap(),
+                    &prompt.as_bytes()[start..end],
+                    "{:?}",
+                    prompt
+                );
+                covered = end;
+            });
+        assert_eq!(covered, prompt.len(), "{:?}", prompt);
+    }
+
+    #[test]
+    fn test_offsets_round_trip() {
+        let tokenizer = shoggoth_tokenizer();
+        for prompt in [
+            "",
+            "Shoggoth",
+            "One day, Lily met a Shoggoth 🐻",
+            " leading  spaces ",
+            "é",
+        ] {
+            assert_offsets_round_trip(&tokenizer, prompt);
+        }
+
+        let mut rng = StdRng::seed_from_u64(24);
+        for _ in 0..20 {
+            let tokenizer = random_tokenizer(&mut rng);
+            for len in [1, 2, 5, 30, 200] {
+                assert_offsets_round_trip(&tokenizer, &random_text(&mut rng, len));
+            }
+        }
+    }
+
+    #[test]
+    fn test_offsets_of_byte_and_special_tokens() {
+        let mut tokenizer = shoggoth_tokenizer();
+        tokenizer.parse_special = true;
+        let (tokens, offsets) = tokenizer
+            .encode_with_offsets("é</s>ot", true, true)
+            .unwrap();
+        let pieces = tokens
+            .iter()
+            .map(|&t| tokenizer.vocab[t as usize].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pieces,
+            vec!["\n<s>\n", " ", "<0xC3>", "<0xA9>", "\n</s>\n", " ", "ot", "\n</s>\n"]
+        );
+        assert_eq!(
+            offsets,
+            vec![
+                (0, 0),
+                (0, 0),
+                (0, 1),
+                (1, 2),
+                (2, 6),
+                (6, 6),
+                (6, 8),
+                (8, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offsets_through_normalizers() {
+        let mut tokenizer = shoggoth_tokenizer();
+        tokenizer.normalizers = vec![
+            Normalizer::Nfkc,
+            Normalizer::Lowercase,
+            Normalizer::RemoveExtraWhitespaces,
+            Normalizer::Prepend(" ".to_string()),
+        ];
+        // the fullwidth O and the composed é come from more bytes than they have
+        let prompt = "\u{FF2F}ot  e\u{301}";
+        let (tokens, offsets) = tokenizer.encode_with_offsets(prompt, false, false).unwrap();
+        assert_eq!(tokens, tokenizer.encode(prompt, false, false).unwrap());
+        let pieces = tokens
+            .iter()
+            .map(|&t| tokenizer.vocab[t as usize].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, vec![" ", "o", "ot", " ", "<0xC3>", "<0xA9>"]);
+        assert_eq!(
+            offsets,
+            vec![(0, 0), (0, 3), (3, 5), (5, 6), (7, 10), (7, 10)]
+        );
+    }
+
+    #[test]
+    fn test_normal_forms_match_whole_text() {
+        let alphabet = [
+            'a', 'e', '\u{301}', '\u{323}', 'Å', 'ﬁ', '\u{1100}', '\u{1161}', '\u{11A8}', '한',
+        ];
+        let mut rng = StdRng::seed_from_u64(24);
+        for _ in 0..200 {
+            let text = (0..rng.gen_range(0..12))
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect::<String>();
+            let chars = text
+                .char_indices()
+                .map(|(i, c)| (c, (i, i + c.len_utf8())))
+                .collect::<Vec<Aligned>>();
+            let formed = |form: fn(&str) -> String| {
+                normalize_form(&chars, form)
+                    .iter()
+                    .map(|&(c, _)| c)
+                    .collect::<String>()
+            };
+            assert_eq!(
+                formed(|s| s.nfc().collect()),
+                text.nfc().collect::<String>()
+            );
+            assert_eq!(
+                formed(|s| s.nfd().collect()),
+                text.nfd().collect::<String>()
+            );
+            assert_eq!(
+                formed(|s| s.nfkc().collect()),
+                text.nfkc().collect::<String>()
+            );
+            assert_eq!(
+                formed(|s| s.nfkd().collect()),
+                text.nfkd().collect::<String>()
+            );
+        }
+    }
+
+    // shoggoth_tokenizer with <sep> as an added token
+    fn sep_tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::with_pieces(&[(" S", 1f32), (" Sh", 2f32), ("<sep>", 0f32)]);
+        tokenizer.added_tokens = vec![tokenizer.token_lookup("<sep>").unwrap()];
+        tokenizer
+    }
+
+    fn assert_round_trip(tokenizer: &Tokenizer, text: &str) {
+        for (bos, eos) in [(false, false), (true, false), (true, true)] {
+            let tokens = tokenizer.encode(text, bos, eos).unwrap();
+            assert_eq!(tokenizer.decode_all(&tokens).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_decode_all_round_trips() {
+        for text in [
+            "",
+            " ",
+            "  leading spaces",
+            "trailing space ",
+            "\x03 abcdef 🐻\x1f",
+            "One day, Lily met a Shoggoth",
+            "\n<s>\n</s>",
+        ] {
+            assert_round_trip(&shoggoth_tokenizer(), text);
+        }
+    }
+
+    #[test]
+    fn test_decode_all_after_added_and_control_tokens() {
+        let tokenizer = sep_tokenizer();
+        for text in ["Sh<sep>Sh", "<sep> Sh", "Sh<sep>", "<sep><sep>", " <sep>  "] {
+            assert_round_trip(&tokenizer, text);
+        }
+
+        // text after a control token in the middle had its own dummy prefix
+        let sh = tokenizer.token_lookup(" Sh").unwrap();
+        let text = tokenizer
+            .decode_all(&[BOS_TOKEN, sh, EOS_TOKEN, sh, EOS_TOKEN])
+            .unwrap();
+        assert_eq!(text, "ShSh");
+        assert!(tokenizer.decode_all(&[tokenizer.vocab_size]).is_err());
+    }
+
+    // pieces to build text from, so that spaces, controls, added tokens and
+    // multi-byte characters meet in every order
+    const PIECES: [&str; 12] = [
+        " ", "  ", "\x03", "\x1f", "\n", "Sh", "oth", "🐻", "é", "e\u{301}", "<sep>", "\n<s>\n",
+    ];
+
+    proptest! {
+        #[test]
+        fn prop_decode_all_round_trips(text in any::<String>()) {
+            assert_round_trip(&shoggoth_tokenizer(), &text);
+        }
+
+        #[test]
+        fn prop_decode_all_round_trips_pieces(
+            pieces in prop::collection::vec(prop::sample::select(PIECES.to_vec()), 0..24)
+        ) {
+            assert_round_trip(&sep_tokenizer(), &pieces.concat());
+        }
+
+        #[test]
+        fn prop_offsets_round_trip(text in any::<String>()) {
+            assert_offsets_round_trip(&sep_tokenizer(), &text);
+        }
     }
 }
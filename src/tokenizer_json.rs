@@ -0,0 +1,917 @@
+use crate::tiktoken::Tiktoken;
+use crate::tokenizer::{self, Normalizer, PieceType, PreTokenizer, PrependScheme, Tokenizer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Builds a Tokenizer from a HuggingFace tokenizer.json, so a checkpoint's own
+// tokenizer can be used without exporting tokenizer.bin through Python.
+//
+// Supported: BPE models, with byte fallback or an unk token, the merges in their
+// rank order, added tokens, the NFC/NFD/NFKC/NFKD, Lowercase, Strip, Prepend and
+// string Replace normalizers and the Metaspace, Whitespace, WhitespaceSplit and
+// Digits pre-tokenizers, alone or in a Sequence. Sentencepiece-BPE models write
+// spaces as a stand-in character, "▁", that is turned back into a space in the
+// vocab, as tokenizer.bin has it.
+//
+// Byte-level BPE models, like GPT-2's and llama 3's, load as a Tiktoken instead.
+// Their pieces spell bytes in GPT-2's alphabet of printable characters, and text
+// is split by a ByteLevel pre-tokenizer with GPT-2's regex or by a Split regex
+// before it. Normalizers, add_prefix_space and added tokens that are not special
+// are not supported there.
+
+// GPT-2's split, used by a ByteLevel pre-tokenizer with use_regex
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+fn field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    value.get(key).filter(|v| !v.is_null())
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> Result<&'a str, String> {
+    field(value, key)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("tokenizer.json: expected a string '{}' in {}", key, value))
+}
+
+fn bool_field(value: &Value, key: &str, default: bool) -> bool {
+    field(value, key)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(default)
+}
+
+fn component_type(value: &Value) -> Result<&str, String> {
+    str_field(value, "type")
+}
+
+fn normalizers(value: Option<&Value>, out: &mut Vec<Normalizer>) -> Result<(), String> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+    let normalizer = match component_type(value)? {
+        "Sequence" => {
+            let Some(Value::Array(sequence)) = field(value, "normalizers") else {
+                return Err("tokenizer.json: Sequence without normalizers".to_string());
+            };
+            return sequence.iter().try_for_each(|n| normalizers(Some(n), out));
+        }
+        "NFC" => Normalizer::Nfc,
+        "NFD" => Normalizer::Nfd,
+        "NFKC" => Normalizer::Nfkc,
+        "NFKD" => Normalizer::Nfkd,
+        "Lowercase" => Normalizer::Lowercase,
+        "Prepend" => Normalizer::Prepend(str_field(value, "prepend")?.to_string()),
+        "Replace" => {
+            let pattern = field(value, "pattern").unwrap_or(&Value::Null);
+            let Some(from) = field(pattern, "String").and_then(|v| v.as_str()) else {
+                return Err(format!(
+                    "tokenizer.json: Replace only supports string patterns, not {}",
+                    pattern
+                ));
+            };
+            Normalizer::Replace(from.to_string(), str_field(value, "content")?.to_string())
+        }
+        "Strip" => Normalizer::Strip {
+            left: bool_field(value, "strip_left", true),
+            right: bool_field(value, "strip_right", true),
+        },
+        other => return Err(format!("tokenizer.json: unsupported normalizer {}", other)),
+    };
+    out.push(normalizer);
+    Ok(())
+}
+
+// the pre-tokenizers, and the space stand-in of a Metaspace
+fn pre_tokenizers(
+    value: Option<&Value>,
+    out: &mut Vec<PreTokenizer>,
+    replacement: &mut Option<String>,
+) -> Result<(), String> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+    let pre_tokenizer = match component_type(value)? {
+        "Sequence" => {
+            let Some(Value::Array(sequence)) = field(value, "pretokenizers") else {
+                return Err("tokenizer.json: Sequence without pretokenizers".to_string());
+            };
+            return sequence
+                .iter()
+                .try_for_each(|p| pre_tokenizers(Some(p), out, replacement));
+        }
+        "Metaspace" => {
+            *replacement = Some(str_field(value, "replacement")?.to_string());
+            // older files only have add_prefix_space
+            let prepend = match field(value, "prepend_scheme").and_then(|v| v.as_str()) {
+                Some("always") => PrependScheme::Always,
+                Some("first") => PrependScheme::First,
+                Some("never") => PrependScheme::Never,
+                Some(other) => {
+                    return Err(format!("tokenizer.json: unknown prepend_scheme {}", other))
+                }
+                None if bool_field(value, "add_prefix_space", true) => PrependScheme::Always,
+                None => PrependScheme::Never,
+            };
+            PreTokenizer::Metaspace {
+                prepend,
+                split: bool_field(value, "split", true),
+            }
+        }
+        "Whitespace" => PreTokenizer::Whitespace,
+        "WhitespaceSplit" => PreTokenizer::WhitespaceSplit,
+        "Digits" => PreTokenizer::Digits {
+            individual: bool_field(value, "individual_digits", false),
+        },
+        "ByteLevel" => return Err("tokenizer.json: byte-level BPE loads as a Tiktoken".to_string()),
+        other => {
+            return Err(format!(
+                "tokenizer.json: unsupported pre-tokenizer {}",
+                other
+            ))
+        }
+    };
+    out.push(pre_tokenizer);
+    Ok(())
+}
+
+// whether the pre-tokenizer, or one in its Sequence, is a ByteLevel
+fn is_byte_level(value: Option<&Value>) -> bool {
+    let Some(value) = value else {
+        return false;
+    };
+    match component_type(value) {
+        Ok("ByteLevel") => true,
+        Ok("Sequence") => match field(value, "pretokenizers") {
+            Some(Value::Array(sequence)) => sequence.iter().any(|p| is_byte_level(Some(p))),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// the split pattern of a byte-level BPE, from a Split before the ByteLevel or
+// the ByteLevel itself
+fn byte_level_pattern(value: &Value, pattern: &mut Option<String>) -> Result<(), String> {
+    let mut set = |p: &str| match pattern {
+        Some(_) => Err("tokenizer.json: more than one split pattern".to_string()),
+        None => {
+            *pattern = Some(p.to_string());
+            Ok(())
+        }
+    };
+    match component_type(value)? {
+        "Sequence" => {
+            let Some(Value::Array(sequence)) = field(value, "pretokenizers") else {
+                return Err("tokenizer.json: Sequence without pretokenizers".to_string());
+            };
+            sequence
+                .iter()
+                .try_for_each(|p| byte_level_pattern(p, pattern))
+        }
+        "ByteLevel" => {
+            if bool_field(value, "add_prefix_space", true) {
+                return Err("tokenizer.json: add_prefix_space is not supported".to_string());
+            }
+            if bool_field(value, "use_regex", true) {
+                set(GPT2_PATTERN)?;
+            }
+            Ok(())
+        }
+        "Split" => {
+            let split = field(value, "pattern").unwrap_or(&Value::Null);
+            let regex = match (field(split, "Regex"), field(split, "String")) {
+                (Some(Value::String(regex)), _) => regex.clone(),
+                (_, Some(Value::String(literal))) => fancy_regex::escape(literal).into_owned(),
+                _ => return Err(format!("tokenizer.json: malformed Split pattern {}", split)),
+            };
+            if field(value, "behavior").and_then(|v| v.as_str()) != Some("Isolated")
+                || bool_field(value, "invert", false)
+            {
+                return Err("tokenizer.json: only Isolated splits are supported".to_string());
+            }
+            set(&regex)
+        }
+        other => Err(format!(
+            "tokenizer.json: unsupported pre-tokenizer {} in byte-level BPE",
+            other
+        )),
+    }
+}
+
+// GPT-2's bytes_to_unicode: the printable bytes are their own characters, the
+// others take the characters from U+0100 on, in order
+fn byte_level_alphabet() -> HashMap<char, u8> {
+    let mut next = 0x100;
+    (0..=255u8)
+        .map(|b| match b {
+            b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff => (b as char, b),
+            _ => {
+                next += 1;
+                (char::from_u32(next - 1).unwrap(), b)
+            }
+        })
+        .collect()
+}
+
+// the BPE model's type and options, which both kinds of BPE share
+fn check_bpe_model(model: &Value) -> Result<(), String> {
+    if let Some(model_type) = field(model, "type") {
+        if model_type != "BPE" {
+            return Err(format!(
+                "tokenizer.json: unsupported model {}, only BPE",
+                model_type
+            ));
+        }
+    }
+    if ["continuing_subword_prefix", "end_of_word_suffix"]
+        .iter()
+        .any(|key| field(model, key).is_some_and(|v| v != ""))
+    {
+        return Err("tokenizer.json: subword prefixes and suffixes are not supported".to_string());
+    }
+    Ok(())
+}
+
+// the pieces of a merge, either "left right" or ["left", "right"]
+fn merge_pieces(merge: &Value) -> Result<(&str, &str), String> {
+    let pieces = match merge {
+        Value::String(merge) => merge.split_once(' '),
+        Value::Array(pair) => match pair.as_slice() {
+            [Value::String(left), Value::String(right)] => Some((left.as_str(), right.as_str())),
+            _ => None,
+        },
+        _ => None,
+    };
+    pieces.ok_or_else(|| format!("tokenizer.json: malformed merge {}", merge))
+}
+
+impl Tokenizer {
+    pub fn from_json_file(path: &str) -> Result<Self, String> {
+        let start = Instant::now();
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let tokenizer = Self::from_json(&json)?;
+        log::info!(
+            "Loaded {} tokens from {} in {:.2?}",
+            tokenizer.vocab_size,
+            path,
+            start.elapsed()
+        );
+        Ok(tokenizer)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let root: Value =
+            serde_json::from_str(json).map_err(|e| format!("tokenizer.json: {}", e))?;
+        let model = field(&root, "model").ok_or("tokenizer.json: no model")?;
+        check_bpe_model(model)?;
+        if bool_field(model, "ignore_merges", false) {
+            return Err("tokenizer.json: ignore_merges is not supported".to_string());
+        }
+
+        let mut normalizer_list = vec![];
+        normalizers(field(&root, "normalizer"), &mut normalizer_list)?;
+        let mut pre_tokenizer_list = vec![];
+        let mut replacement = None;
+        pre_tokenizers(
+            field(&root, "pre_tokenizer"),
+            &mut pre_tokenizer_list,
+            &mut replacement,
+        )?;
+
+        // sentencepiece-BPE writes spaces as "▁", either through a Metaspace or
+        // by replacing them in the normalizer. the vocab gets its spaces back and
+        // the normalizers that only swap them are dropped
+        let replacement = replacement.or_else(|| {
+            normalizer_list.iter().find_map(|n| match n {
+                Normalizer::Replace(from, to) if from == " " => Some(to.clone()),
+                _ => None,
+            })
+        });
+        let unescape = |piece: &str| match &replacement {
+            Some(r) => piece.replace(r.as_str(), " "),
+            None => piece.to_string(),
+        };
+        let normalizer_list = normalizer_list
+            .into_iter()
+            .filter_map(|n| match n {
+                Normalizer::Prepend(p) => Some(Normalizer::Prepend(unescape(&p))),
+                Normalizer::Replace(from, to) => {
+                    let (from, to) = (unescape(&from), unescape(&to));
+                    (from != to).then_some(Normalizer::Replace(from, to))
+                }
+                n => Some(n),
+            })
+            .collect::<Vec<Normalizer>>();
+
+        let Some(Value::Object(model_vocab)) = field(model, "vocab") else {
+            return Err("tokenizer.json: the model has no vocab".to_string());
+        };
+        let empty = vec![];
+        let added = match field(&root, "added_tokens") {
+            Some(Value::Array(added)) => added,
+            _ => &empty,
+        };
+
+        let id_of = |value: &Value| {
+            value
+                .as_u64()
+                .map(|id| id as usize)
+                .ok_or_else(|| format!("tokenizer.json: bad token id {}", value))
+        };
+        let mut pieces: Vec<Option<String>> = vec![];
+        let mut set = |id: usize, piece: String| {
+            if pieces.len() <= id {
+                pieces.resize(id + 1, None);
+            }
+            pieces[id] = Some(piece);
+        };
+        for (piece, id) in model_vocab {
+            set(id_of(id)?, unescape(piece));
+        }
+        let mut added_tokens = vec![];
+        let mut special_tokens = vec![];
+        for token in added {
+            let id = id_of(field(token, "id").unwrap_or(&Value::Null))?;
+            let content = str_field(token, "content")?;
+            set(id, content.to_string());
+            if bool_field(token, "special", false) {
+                special_tokens.push(id as u32);
+            } else {
+                added_tokens.push(id as u32);
+            }
+        }
+        let vocab = pieces
+            .into_iter()
+            .enumerate()
+            .map(|(id, piece)| piece.ok_or_else(|| format!("tokenizer.json: no token {}", id)))
+            .collect::<Result<Vec<String>, String>>()?;
+
+        let ids = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.as_str(), id as u32))
+            .collect::<HashMap<&str, u32>>();
+        let lookup = |piece: &str| {
+            ids.get(piece)
+                .copied()
+                .ok_or_else(|| format!("tokenizer.json: merge of unknown piece {:?}", piece))
+        };
+
+        // the lower the rank, the earlier the merge, so its priority is -rank
+        let Some(Value::Array(merge_list)) = field(model, "merges") else {
+            return Err("tokenizer.json: the model has no merges".to_string());
+        };
+        let mut merges = HashMap::new();
+        let mut vocab_scores = vec![0f32; vocab.len()];
+        let mut scored = vec![false; vocab.len()];
+        for (rank, merge) in merge_list.iter().enumerate() {
+            let (left, right) = merge_pieces(merge)?;
+            let (left, right) = (unescape(left), unescape(right));
+            let id = lookup(&format!("{}{}", left, right))?;
+            let priority = -(rank as f32);
+            merges
+                .entry((lookup(&left)?, lookup(&right)?))
+                .or_insert((id, priority));
+            if !scored[id as usize] {
+                vocab_scores[id as usize] = priority;
+                scored[id as usize] = true;
+            }
+        }
+
+        let unk_token = match field(model, "unk_token").and_then(|v| v.as_str()) {
+            Some(unk) => Some(
+                *ids.get(unk)
+                    .ok_or_else(|| format!("tokenizer.json: unknown unk_token {}", unk))?,
+            ),
+            None => None,
+        };
+
+        let byte_fallback = bool_field(model, "byte_fallback", false);
+        let piece_types = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| {
+                let id = id as u32;
+                if unk_token == Some(id) {
+                    PieceType::Unknown
+                } else if special_tokens.contains(&id) {
+                    PieceType::Control
+                } else if added_tokens.contains(&id) {
+                    PieceType::UserDefined
+                } else if byte_fallback && tokenizer::is_byte_piece(piece) {
+                    PieceType::Byte
+                } else {
+                    PieceType::Normal
+                }
+            })
+            .collect();
+
+        let max_token_length = vocab.iter().map(|v| v.len()).max().unwrap_or(0);
+        let mut tokenizer =
+            Tokenizer::from_merges(vocab, vocab_scores, piece_types, max_token_length, merges);
+        tokenizer.normalizers = normalizer_list;
+        tokenizer.pre_tokenizers = pre_tokenizer_list;
+        tokenizer.byte_fallback = byte_fallback;
+        tokenizer.fuse_unk = bool_field(model, "fuse_unk", false);
+        tokenizer.unk_token = unk_token.unwrap_or(0);
+        tokenizer.added_tokens = added_tokens;
+        Ok(tokenizer)
+    }
+}
+
+impl Tiktoken {
+    pub fn from_json_file(path: &str) -> Result<Self, String> {
+        let start = Instant::now();
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let tokenizer = Self::from_json(&json)?;
+        log::info!(
+            "Loaded {} tokens from {} in {:.2?}",
+            tokenizer.vocab_size,
+            path,
+            start.elapsed()
+        );
+        Ok(tokenizer)
+    }
+
+    // a byte-level BPE tokenizer.json
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let root: Value =
+            serde_json::from_str(json).map_err(|e| format!("tokenizer.json: {}", e))?;
+        let model = field(&root, "model").ok_or("tokenizer.json: no model")?;
+        check_bpe_model(model)?;
+        if field(&root, "normalizer").is_some() {
+            return Err(
+                "tokenizer.json: normalizers are not supported with byte-level BPE".to_string(),
+            );
+        }
+        let pre_tokenizer = field(&root, "pre_tokenizer");
+        if !is_byte_level(pre_tokenizer) {
+            return Err("tokenizer.json: no ByteLevel pre-tokenizer".to_string());
+        }
+        let mut pattern = None;
+        byte_level_pattern(pre_tokenizer.unwrap(), &mut pattern)?;
+        let pattern = pattern.ok_or("tokenizer.json: no split pattern")?;
+
+        let alphabet = byte_level_alphabet();
+        let bytes_of = |piece: &str| {
+            piece
+                .chars()
+                .map(|c| alphabet.get(&c).copied())
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("tokenizer.json: {:?} is not byte-level", piece))
+        };
+
+        let Some(Value::Object(model_vocab)) = field(model, "vocab") else {
+            return Err("tokenizer.json: the model has no vocab".to_string());
+        };
+        let id_of = |value: &Value| {
+            value
+                .as_u64()
+                .map(|id| id as usize)
+                .ok_or_else(|| format!("tokenizer.json: bad token id {}", value))
+        };
+        let mut tokens: Vec<Option<Vec<u8>>> = vec![];
+        let mut set = |id: usize, token: Vec<u8>| {
+            if tokens.len() <= id {
+                tokens.resize(id + 1, None);
+            }
+            tokens[id] = Some(token);
+        };
+        let mut ids = HashMap::new();
+        for (piece, id) in model_vocab {
+            let id = id_of(id)?;
+            set(id, bytes_of(piece)?);
+            ids.insert(piece.as_str(), id as u32);
+        }
+
+        let mut special_tokens = HashMap::new();
+        if let Some(Value::Array(added)) = field(&root, "added_tokens") {
+            for token in added {
+                let id = id_of(field(token, "id").unwrap_or(&Value::Null))?;
+                let content = str_field(token, "content")?;
+                if !bool_field(token, "special", false) {
+                    return Err(format!(
+                        "tokenizer.json: added token {:?} is not special",
+                        content
+                    ));
+                }
+                set(id, content.as_bytes().to_vec());
+                special_tokens.insert(content.to_string(), id as u32);
+            }
+        }
+        let vocab = tokens
+            .into_iter()
+            .enumerate()
+            .map(|(id, token)| token.ok_or_else(|| format!("tokenizer.json: no token {}", id)))
+            .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+        let lookup = |piece: &str| {
+            ids.get(piece)
+                .copied()
+                .ok_or_else(|| format!("tokenizer.json: merge of unknown piece {:?}", piece))
+        };
+        let Some(Value::Array(merge_list)) = field(model, "merges") else {
+            return Err("tokenizer.json: the model has no merges".to_string());
+        };
+        let mut merges = HashMap::new();
+        for (rank, merge) in merge_list.iter().enumerate() {
+            let (left, right) = merge_pieces(merge)?;
+            lookup(&format!("{}{}", left, right))?;
+            merges
+                .entry((lookup(left)?, lookup(right)?))
+                .or_insert(rank as u32);
+        }
+
+        Tiktoken::from_parts(
+            vocab,
+            special_tokens,
+            Some(merges),
+            &pattern,
+            bool_field(model, "ignore_merges", false),
+        )
+        .map_err(|e| format!("tokenizer.json: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{StreamDecoder, Tokenize};
+    use serde_json::json;
+
+    // a sentencepiece-BPE tokenizer.json as transformers writes it for llama:
+    // <unk>, BOS, EOS, the byte tokens, then the given pieces and merges
+    fn llama(pieces: &[&str], merges: &[&str]) -> Value {
+        let mut vocab = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+        vocab.extend((0..=255).map(|b| format!("<0x{:02X}>", b)));
+        vocab.extend(pieces.iter().map(|p| p.to_string()));
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.clone(), json!(id)))
+            .collect::<serde_json::Map<String, Value>>();
+
+        json!({
+            "added_tokens": [
+                {"id": 0, "content": "<unk>", "special": true},
+                {"id": 1, "content": "<s>", "special": true},
+                {"id": 2, "content": "</s>", "special": true},
+            ],
+            "normalizer": {
+                "type": "Sequence",
+                "normalizers": [
+                    {"type": "Prepend", "prepend": "▁"},
+                    {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+                ],
+            },
+            "pre_tokenizer": null,
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": "<unk>",
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": true,
+                "byte_fallback": true,
+                "vocab": vocab,
+                "merges": merges,
+            },
+        })
+    }
+
+    fn load(json: &Value) -> Tokenizer {
+        Tokenizer::from_json(&json.to_string()).unwrap()
+    }
+
+    fn encode_pieces(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        tokenizer
+            .encode(text, false, false)
+            .unwrap()
+            .iter()
+            .map(|&t| tokenizer.vocab[t as usize].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_sentencepiece_bpe() {
+        let tokenizer = load(&llama(
+            &["▁", "a", "b", "c", "▁a", "bc", "▁abc"],
+            &["▁ a", "b c", "▁a bc"],
+        ));
+        assert_eq!(tokenizer.vocab[259], " ");
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec![" abc"]);
+        assert_eq!(encode_pieces(&tokenizer, "ab c"), vec![" a", "b", " ", "c"]);
+        assert_eq!(
+            encode_pieces(&tokenizer, "é"),
+            vec![" ", "<0xC3>", "<0xA9>"]
+        );
+
+        let tokens = tokenizer.encode("ab cé", true, false).unwrap();
+        assert_eq!(tokens[0], 1);
+        let mut decoder = StreamDecoder::new(&tokenizer, 1);
+        let text = tokens[1..]
+            .iter()
+            .map(|&t| decoder.push(t).unwrap())
+            .collect::<String>();
+        assert_eq!(text, "ab cé");
+    }
+
+    #[test]
+    fn test_merges_follow_rank() {
+        // the pair listed first merges first, wherever it is in the word
+        let tokenizer = load(&llama(&["▁", "a", "b", "c", "bc", "▁a"], &["b c", "▁ a"]));
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec![" a", "bc"]);
+        let tokenizer = load(&llama(&["▁", "a", "b", "c", "ab", "▁a"], &["a b", "▁ a"]));
+        assert_eq!(encode_pieces(&tokenizer, "abc"), vec![" ", "ab", "c"]);
+
+        // merges also come as pairs
+        let mut json = llama(&["▁", "a", "b", "c", "ab", "▁a"], &[]);
+        json["model"]["merges"] = json!([["a", "b"], ["▁", "a"]]);
+        assert_eq!(encode_pieces(&load(&json), "abc"), vec![" ", "ab", "c"]);
+    }
+
+    #[test]
+    fn test_metaspace() {
+        let mut json = llama(&["▁", "a", "a▁", "▁a"], &["a ▁", "▁ a"]);
+        json["normalizer"] = Value::Null;
+        json["pre_tokenizer"] = json!({
+            "type": "Metaspace",
+            "replacement": "▁",
+            "prepend_scheme": "always",
+            "split": true,
+        });
+        let tokenizer = load(&json);
+        // split, "a▁" never forms across the words
+        assert_eq!(encode_pieces(&tokenizer, "a a"), vec![" a", " a"]);
+        // no second dummy prefix before a leading space
+        assert_eq!(encode_pieces(&tokenizer, " a"), vec![" a"]);
+
+        json["pre_tokenizer"]["split"] = json!(false);
+        let tokenizer = load(&json);
+        assert_eq!(encode_pieces(&tokenizer, "a a"), vec![" ", "a ", "a"]);
+    }
+
+    #[test]
+    fn test_added_tokens() {
+        let mut json = llama(&["▁", "a", "▁a", "<sep>", "<|x|>"], &["▁ a"]);
+        json["normalizer"] = Value::Null;
+        json["pre_tokenizer"] = json!({
+            "type": "Metaspace",
+            "replacement": "▁",
+            "prepend_scheme": "first",
+        });
+        let added = json["added_tokens"].as_array_mut().unwrap();
+        added.push(json!({"id": 262, "content": "<sep>", "special": false}));
+        added.push(json!({"id": 263, "content": "<|x|>", "special": true}));
+        let tokenizer = load(&json);
+        assert_eq!(tokenizer.added_tokens, vec![262]);
+
+        // the added token is matched whole, and only the first text is prefixed
+        assert_eq!(
+            encode_pieces(&tokenizer, "a<sep>a"),
+            vec![" a", "<sep>", "a"]
+        );
+        json["pre_tokenizer"]["prepend_scheme"] = json!("always");
+        assert_eq!(
+            encode_pieces(&load(&json), "a<sep>a"),
+            vec![" a", "<sep>", " a"]
+        );
+
+        // special tokens stay text unless parsed
+        assert!(!tokenizer
+            .encode("<|x|>", false, false)
+            .unwrap()
+            .contains(&263));
+        let mut tokenizer = tokenizer;
+        tokenizer.parse_special = true;
+        assert_eq!(
+            encode_pieces(&tokenizer, "<|x|>a<s>"),
+            vec!["<|x|>", "a", "<s>"]
+        );
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let mut json = llama(&["[INST]", "<|eot_id|>", "<pad>"], &[]);
+        let added = json["added_tokens"].as_array_mut().unwrap();
+        added.push(json!({"id": 259, "content": "[INST]", "special": true}));
+        added.push(json!({"id": 260, "content": "<|eot_id|>", "special": true}));
+        added.push(json!({"id": 261, "content": "<pad>", "special": true}));
+        let mut tokenizer = load(&json);
+
+        assert_eq!(tokenizer.special_tokens.len(), 6);
+        assert_eq!(tokenizer.special_tokens["[INST]"], 259);
+        assert_eq!((tokenizer.bos_token, tokenizer.eos_token), (1, 2));
+        assert_eq!(tokenizer.pad_token, Some(261));
+        assert_eq!(tokenizer.end_tokens, vec![1, 2, 260]);
+
+        tokenizer.parse_special = true;
+        assert_eq!(
+            tokenizer.encode("[INST]<|eot_id|>", true, false).unwrap(),
+            vec![1, 259, 260]
+        );
+    }
+
+    #[test]
+    fn test_normalizers_and_pre_tokenizers() {
+        let json = json!({
+            "normalizer": {"type": "Sequence", "normalizers": [{"type": "NFKC"}, {"type": "Lowercase"}]},
+            "pre_tokenizer": {
+                "type": "Sequence",
+                "pretokenizers": [
+                    {"type": "Whitespace"},
+                    {"type": "Digits", "individual_digits": true},
+                ],
+            },
+            "model": {
+                "type": "BPE",
+                "unk_token": "[UNK]",
+                "fuse_unk": true,
+                "vocab": {"[UNK]": 0, "h": 1, "i": 2, ",": 3, "1": 4, "2": 5, "hi": 6},
+                "merges": ["h i"],
+            },
+        });
+        let tokenizer = load(&json);
+        // the ligature is "fi" after NFKC, f and the é run are unknown
+        assert_eq!(
+            tokenizer.encode("HI, ﬁéé 12", false, false).unwrap(),
+            vec![6, 3, 0, 2, 0, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut json = llama(&[], &[]);
+        json["pre_tokenizer"] = json!({"type": "ByteLevel", "add_prefix_space": false});
+        assert!(Tokenizer::from_json(&json.to_string()).is_err());
+
+        let mut json = llama(&[], &[]);
+        json["model"]["type"] = json!("WordPiece");
+        assert!(Tokenizer::from_json(&json.to_string()).is_err());
+
+        // a gap in the ids
+        let mut json = llama(&[], &[]);
+        json["added_tokens"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({"id": 300, "content": "<pad>", "special": true}));
+        assert!(Tokenizer::from_json(&json.to_string()).is_err());
+    }
+
+    // a byte-level tokenizer.json as GPT-2 has it: the bytes in GPT-2's alphabet,
+    // the given pieces and merges, then <|endoftext|>
+    fn gpt2(pieces: &[&str], merges: &[&str]) -> Value {
+        let mut bytes = byte_level_alphabet()
+            .into_iter()
+            .map(|(c, b)| (b, c))
+            .collect::<Vec<(u8, char)>>();
+        bytes.sort();
+        let mut vocab = bytes
+            .iter()
+            .map(|(_, c)| c.to_string())
+            .collect::<Vec<String>>();
+        vocab.extend(pieces.iter().map(|p| p.to_string()));
+        vocab.push("<|endoftext|>".to_string());
+        let eot = vocab.len() - 1;
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.clone(), json!(id)))
+            .collect::<serde_json::Map<String, Value>>();
+
+        json!({
+            "added_tokens": [{"id": eot, "content": "<|endoftext|>", "special": true}],
+            "normalizer": null,
+            "pre_tokenizer": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": true,
+            },
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "use_regex": true},
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": "",
+                "end_of_word_suffix": "",
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": merges,
+            },
+        })
+    }
+
+    fn load_byte_level(json: &Value) -> Tiktoken {
+        Tiktoken::from_json(&json.to_string()).unwrap()
+    }
+
+    fn byte_level_pieces(tokenizer: &Tiktoken, text: &str) -> Vec<String> {
+        tokenizer
+            .encode(text, false, false)
+            .unwrap()
+            .iter()
+            .map(|&t| tokenizer.piece(t))
+            .collect()
+    }
+
+    #[test]
+    fn test_byte_level_bpe() {
+        // Ġ is the space and Ã© the two bytes of é
+        let tokenizer = load_byte_level(&gpt2(
+            &["Ġt", "he", "Ġthe", "Ã©"],
+            &["Ġ t", "h e", "Ġt he", "Ã ©"],
+        ));
+        assert_eq!(tokenizer.vocab_size, 256 + 5);
+        assert_eq!(
+            byte_level_pieces(&tokenizer, "the the é"),
+            vec!["t", "he", " the", " ", "é"]
+        );
+
+        let eot = tokenizer.special_tokens["<|endoftext|>"];
+        assert_eq!((tokenizer.bos_token, tokenizer.eos_token), (eot, eot));
+        assert_eq!(tokenizer.end_tokens, vec![eot]);
+        let tokens = tokenizer.encode("the\u{1f43b}!", true, true).unwrap();
+        assert_eq!(tokens.len(), 2 + 2 + 4 + 1);
+        assert_eq!(tokenizer.decode_all(&tokens).unwrap(), "the\u{1f43b}!");
+
+        let mut tokenizer = tokenizer;
+        tokenizer.parse_special = true;
+        assert_eq!(
+            tokenizer.encode("<|endoftext|>he", false, false).unwrap(),
+            vec![eot, 257]
+        );
+    }
+
+    #[test]
+    fn test_byte_level_merges_follow_pairs() {
+        // only listed pairs merge, "a" and "bc" never make "abc"
+        let tokenizer = load_byte_level(&gpt2(&["ab", "bc", "abc"], &["b c", "a b", "ab c"]));
+        assert_eq!(byte_level_pieces(&tokenizer, "abc"), vec!["a", "bc"]);
+        let tokenizer = load_byte_level(&gpt2(&["ab", "bc", "abc"], &["a b", "b c", "ab c"]));
+        assert_eq!(byte_level_pieces(&tokenizer, "abc"), vec!["abc"]);
+    }
+
+    #[test]
+    fn test_byte_level_split_and_ignore_merges() {
+        // llama 3 splits with a regex before a ByteLevel that only maps bytes
+        let mut json = gpt2(&["ab", "abc"], &["a b"]);
+        json["pre_tokenizer"] = json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": {"Regex": "\\p{L}+"},
+                    "behavior": "Isolated",
+                    "invert": false,
+                },
+                {"type": "ByteLevel", "add_prefix_space": false, "use_regex": false},
+            ],
+        });
+        // "!? " between the matches is a piece of its own
+        let tokenizer = load_byte_level(&json);
+        assert_eq!(
+            byte_level_pieces(&tokenizer, "abc!? ab"),
+            vec!["ab", "c", "!", "?", " ", "ab"]
+        );
+
+        // a piece in the vocab is taken whole
+        json["model"]["ignore_merges"] = json!(true);
+        let tokenizer = load_byte_level(&json);
+        assert_eq!(
+            byte_level_pieces(&tokenizer, "abc ab"),
+            vec!["abc", " ", "ab"]
+        );
+    }
+
+    #[test]
+    fn test_byte_level_unsupported() {
+        assert!(Tokenizer::from_json(&gpt2(&[], &[]).to_string()).is_err());
+
+        let mut json = gpt2(&[], &[]);
+        json["pre_tokenizer"]["add_prefix_space"] = json!(true);
+        assert!(Tiktoken::from_json(&json.to_string()).is_err());
+
+        let mut json = gpt2(&[], &[]);
+        json["normalizer"] = json!({"type": "NFC"});
+        assert!(Tiktoken::from_json(&json.to_string()).is_err());
+
+        let mut json = gpt2(&[], &[]);
+        json["added_tokens"][0]["special"] = json!(false);
+        assert!(Tiktoken::from_json(&json.to_string()).is_err());
+
+        // a sentencepiece-BPE is not byte-level
+        assert!(Tiktoken::from_json(&llama(&[], &[]).to_string()).is_err());
+    }
+
+    // tokenizer.json of the llama 2 checkpoints on the HuggingFace hub, against
+    // the ids from sentencepiece
+    #[test]
+    #[ignore = "needs the llama 2 tokenizer.json as assets/tokenizer.json and assets/tokenizer_golden.json"]
+    fn test_golden_token_ids() {
+        Tokenizer::from_json_file("assets/tokenizer.json")
+            .unwrap()
+            .assert_golden_token_ids();
+    }
+}
@@ -1,9 +1,11 @@
 use crate::maths::mat_mul;
 use crate::utils::{read_file_to_struct, read_variable_length_data};
 use bytemuck::{Pod, Zeroable};
+use log::{debug, info};
 use std::fs::File;
 use std::io::{self, Seek};
 use std::sync::Arc;
+use std::time::Instant;
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, Debug)]
@@ -39,7 +41,7 @@ pub struct TransformerWeights {
     pub wcls: Arc<[f32]>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RunState {
     // current wave of activations
     pub x: Box<[f32]>,      // activation at current time stamp (dim,)
@@ -91,6 +93,17 @@ impl RunState {
             xb2,
         })
     }
+
+    // copies the kv cache of the first len positions, e.g. to fork a sequence
+    pub fn copy_kv_prefix(self: &mut Self, other: &RunState, config: &Config, len: usize) {
+        let kv_dim = ((config.dim * config.n_kv_heads) / config.n_heads) as usize;
+        let layer_size = config.seq_len as usize * kv_dim;
+        for l in 0..config.n_layers as usize {
+            let range = l * layer_size..l * layer_size + len * kv_dim;
+            self.key_cache[range.clone()].copy_from_slice(&other.key_cache[range.clone()]);
+            self.value_cache[range.clone()].copy_from_slice(&other.value_cache[range]);
+        }
+    }
 }
 
 impl TransformerWeights {
@@ -102,7 +115,7 @@ impl TransformerWeights {
 
         let head_size = config.dim / config.n_heads;
 
-        println!(
+        debug!(
             "Reading token embeddings - size: {}...",
             config.vocab_size * config.dim
         );
@@ -111,14 +124,14 @@ impl TransformerWeights {
             (config.vocab_size * config.dim) as usize,
         )?);
 
-        println!(
+        debug!(
             "Reading attention weights - size: {}...",
             config.n_layers * config.dim
         );
         let rms_att_weight =
             read_variable_length_data::<f32>(model_file, (config.n_layers * config.dim) as usize)?;
 
-        println!(
+        debug!(
             "Reading wq - size: {}...",
             config.n_layers * config.dim * config.n_heads * head_size
         );
@@ -126,7 +139,7 @@ impl TransformerWeights {
             model_file,
             (config.n_layers * config.dim * config.n_heads * head_size) as usize,
         )?;
-        println!(
+        debug!(
             "Reading wk - size: {}...",
             config.n_layers * config.dim * config.n_kv_heads * head_size
         );
@@ -134,7 +147,7 @@ impl TransformerWeights {
             model_file,
             (config.n_layers * config.dim * config.n_kv_heads * head_size) as usize,
         )?;
-        println!(
+        debug!(
             "Reading wv - size: {}...",
             config.n_layers * config.dim * config.n_kv_heads * head_size
         );
@@ -142,7 +155,7 @@ impl TransformerWeights {
             model_file,
             (config.n_layers * config.dim * config.n_kv_heads * head_size) as usize,
         )?;
-        println!(
+        debug!(
             "Reading wo - size: {}...",
             config.n_layers * config.dim * config.n_heads * head_size
         );
@@ -151,14 +164,14 @@ impl TransformerWeights {
             (config.n_layers * config.dim * config.n_heads * head_size) as usize,
         )?;
 
-        println!(
+        debug!(
             "Reading rms ffn weights - size: {}...",
             config.n_layers * config.dim
         );
         let rms_ffn_weight =
             read_variable_length_data::<f32>(model_file, (config.n_layers * config.dim) as usize)?;
 
-        println!(
+        debug!(
             "Reading w1 - size: {}...",
             config.n_layers * config.dim * config.hidden_dim
         );
@@ -167,7 +180,7 @@ impl TransformerWeights {
             (config.n_layers * config.dim * config.hidden_dim) as usize,
         )?;
 
-        println!(
+        debug!(
             "Reading w2 - size: {}...",
             config.n_layers * config.dim * config.hidden_dim
         );
@@ -176,7 +189,7 @@ impl TransformerWeights {
             (config.n_layers * config.dim * config.hidden_dim) as usize,
         )?;
 
-        println!(
+        debug!(
             "Reading w3 - size: {}...",
             config.n_layers * config.dim * config.hidden_dim
         );
@@ -185,7 +198,7 @@ impl TransformerWeights {
             (config.n_layers * config.dim * config.hidden_dim) as usize,
         )?;
 
-        println!("Reading rms final weight - size: {}...", config.dim);
+        debug!("Reading rms final weight - size: {}...", config.dim);
         let rms_final_weight = read_variable_length_data::<f32>(model_file, (config.dim) as usize)?;
 
         model_file.seek_relative(head_size as i64)?; //skip what used to be freq_cis_real and freq_cis_imag (for RoPE)
@@ -194,7 +207,7 @@ impl TransformerWeights {
             token_embedding_table.clone()
         } else {
             let stream_position = model_file.stream_position()?;
-            println!(
+            debug!(
                 "Reading wcls - size: {}...",
                 model_file_size - stream_position
             );
@@ -227,17 +240,19 @@ impl Transformer {
     pub fn new(model_file_path: &str) -> io::Result<Self> {
         let mut model_file = File::open(model_file_path)?;
 
-        println!("Loading config...");
+        let start = Instant::now();
+
+        info!("Loading config from {}...", model_file_path);
         let mut config = read_file_to_struct::<Config>(&mut model_file)?;
-        println!("{:?}", config);
+        debug!("{:?}", config);
 
-        println!("Loading weights...");
+        info!("Loading weights...");
         let transformer_weights = TransformerWeights::new(&mut model_file, &mut config)?;
 
-        println!("Initialising state...");
+        info!("Initialising state...");
         let state = RunState::new(&config)?;
 
-        println!("Done.");
+        info!("Loaded {} in {:.2?}", model_file_path, start.elapsed());
 
         Ok(Transformer {
             config,
@@ -448,6 +463,12 @@ impl Transformer {
     //         *e /= sum;
     //     });
     // }
+    pub fn log_softmax(x: &mut [f32]) {
+        let max_val = x.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = x.iter().map(|&e| (e - max_val).exp()).sum::<f32>().ln() + max_val;
+        x.iter_mut().for_each(|e| *e -= log_sum);
+    }
+
     pub fn softmax(x: &mut [f32]) {
         // Step 1: Find the maximum value for numerical stability.
         let max_val = x.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
@@ -466,3 +487,42 @@ impl Transformer {
             });
     }
 }
+
+#[cfg(test)]
+impl Transformer {
+    // small model with uniformly random weights and shared classifier, for tests
+    pub fn random(config: Config, seed: u64) -> Self {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut weights = |size: i32| {
+            (0..size)
+                .map(|_| rng.gen_range(-0.5f32..0.5f32))
+                .collect::<Box<[f32]>>()
+        };
+
+        let kv_dim = (config.dim * config.n_kv_heads) / config.n_heads;
+        let token_embedding_table: Arc<[f32]> = Arc::from(weights(config.vocab_size * config.dim));
+        let transformer_weights = TransformerWeights {
+            rms_att_weight: vec![1f32; (config.n_layers * config.dim) as usize].into_boxed_slice(),
+            rms_ffn_weight: vec![1f32; (config.n_layers * config.dim) as usize].into_boxed_slice(),
+            wq: weights(config.n_layers * config.dim * config.dim),
+            wk: weights(config.n_layers * config.dim * kv_dim),
+            wv: weights(config.n_layers * config.dim * kv_dim),
+            wo: weights(config.n_layers * config.dim * config.dim),
+            w1: weights(config.n_layers * config.dim * config.hidden_dim),
+            w2: weights(config.n_layers * config.dim * config.hidden_dim),
+            w3: weights(config.n_layers * config.dim * config.hidden_dim),
+            rms_final_weight: vec![1f32; config.dim as usize].into_boxed_slice(),
+            wcls: token_embedding_table.clone(),
+            token_embedding_table,
+        };
+
+        Transformer {
+            config,
+            transformer_weights,
+            state: RunState::new(&config).unwrap(),
+        }
+    }
+}